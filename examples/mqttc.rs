@@ -12,7 +12,11 @@ use log::{trace, debug, error, info};
 use mqtt_async_client::{
     client::{
         Client,
+        Endpoint,
+        EndpointPolicy,
         KeepAlive,
+        LastWill,
+        ProtocolVersion,
         Publish as PublishOpts,
         QoS,
         Subscribe as SubscribeOpts,
@@ -20,9 +24,14 @@ use mqtt_async_client::{
     },
     Error,
     Result,
+    util::{
+        load_certs_file,
+        load_private_key_file,
+    },
 };
+#[cfg(feature = "websocket")]
+use mqtt_async_client::client::WebSocketOptions;
 use rustls;
-use std::io::Cursor;
 use structopt::StructOpt;
 use tokio::time::Duration;
 use webpki_roots;
@@ -49,6 +58,16 @@ struct Args {
     #[structopt(long, default_value="1883")]
     port: u16,
 
+    /// Another broker to try if connecting to --host fails, formatted
+    /// as "host:port". May be repeated.
+    #[structopt(long, number_of_values(1))]
+    fallback_endpoint: Vec<String>,
+
+    /// How to choose which broker to connect to when
+    /// --fallback-endpoint is used.
+    #[structopt(long, default_value("round-robin"), possible_values(&["round-robin", "primary"]))]
+    endpoint_policy: String,
+
     /// Client ID to identify as, optional.
     #[structopt(long)]
     client_id: Option<String>,
@@ -63,6 +82,29 @@ struct Args {
     #[structopt(long)]
     tls_mozilla_root_cas: bool,
 
+    /// Authenticate with a client certificate: the path to a PEM or
+    /// DER file containing the certificate chain. Requires
+    /// --tls-client-key and a CA option.
+    #[structopt(long, requires("tls-client-key"))]
+    tls_client_cert: Option<String>,
+
+    /// Path to a PEM or DER file containing the private key for
+    /// --tls-client-cert, in PKCS#8 (RSA or EC) or PKCS#1 RSA format.
+    #[structopt(long, requires("tls-client-cert"))]
+    tls_client_key: Option<String>,
+
+    /// Connect over WebSocket and set the HTTP path to request,
+    /// e.g. "/mqtt". Uses wss:// when TLS is enabled.
+    #[cfg(feature = "websocket")]
+    #[structopt(long)]
+    websocket_path: Option<String>,
+
+    /// Extra header for the WebSocket handshake, formatted as
+    /// "Name: value". May be repeated.
+    #[cfg(feature = "websocket")]
+    #[structopt(long, number_of_values(1))]
+    websocket_header: Vec<String>,
+
     /// Keepalive interval in seconds
     #[structopt(long, default_value("30"))]
     keep_alive: u16,
@@ -73,6 +115,34 @@ struct Args {
 
     #[structopt(long, default_value("true"), possible_values(&["true", "false"]))]
     auto_connect: String,
+
+    /// Connect with a clean session. Set to false to resume a
+    /// persistent session, which requires --client-id.
+    #[structopt(long, default_value("true"), possible_values(&["true", "false"]))]
+    clean_session: String,
+
+    /// Topic for the broker to publish a last will message to if the
+    /// connection is lost, optional.
+    #[structopt(long)]
+    will_topic: Option<String>,
+
+    /// Last will message payload.
+    #[structopt(long, default_value(""))]
+    will_message: String,
+
+    /// Quality of service code to use for the last will message.
+    #[structopt(long,
+                possible_values(&["0", "1", "2"]),
+                default_value("0"))]
+    will_qos: u8,
+
+    /// Set the retain flag on the last will message.
+    #[structopt(long)]
+    will_retain: bool,
+
+    /// MQTT protocol version to connect with.
+    #[structopt(long, default_value("3.1.1"), possible_values(&["3.1.1", "5"]))]
+    protocol_version: String,
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -157,6 +227,12 @@ async fn subscribe(sub_args: Subscribe, args: Args) -> Result<()> {
     if sub_args.topic.len() == 0 {
         return Err(Error::from("You must subscribe to at least one topic."));
     }
+    let mut events = client.events();
+    tokio::spawn(async move {
+        while let Some(e) = events.next().await {
+            info!("Connection event {:?}", e);
+        }
+    });
     client.connect().await?;
     let subopts = SubscribeOpts::new(sub_args.topic.iter().map(|t|
         SubscribeTopic { qos: int_to_qos(sub_args.qos), topic_path: t.clone() }
@@ -174,8 +250,26 @@ async fn subscribe(sub_args: Subscribe, args: Args) -> Result<()> {
 
 fn client_from_args(args: Args) -> Result<Client> {
     let mut b = Client::builder();
-    b.set_host(args.host)
-     .set_port(args.port)
+    if args.fallback_endpoint.is_empty() {
+        b.set_host(args.host)
+         .set_port(args.port);
+    } else {
+        let mut eps = vec![Endpoint::new(args.host, args.port)];
+        for s in args.fallback_endpoint.iter() {
+            let mut parts = s.rsplitn(2, ':');
+            let port = parts.next().expect("at least one part").parse::<u16>()
+                .map_err(|_| Error::from(format!("Bad endpoint port in '{}'", s)))?;
+            let host = parts.next()
+                .ok_or_else(|| Error::from(format!("Bad endpoint '{}'", s)))?;
+            eps.push(Endpoint::new(host.to_owned(), port));
+        }
+        b.set_endpoints(eps);
+    }
+    b.set_endpoint_policy(match args.endpoint_policy.as_str() {
+         "round-robin" => EndpointPolicy::RoundRobin,
+         "primary" => EndpointPolicy::Primary,
+         _ => panic!("Bad validation"),
+     })
      .set_username(args.username)
      .set_password(args.password.map(|s| s.as_bytes().to_vec()))
      .set_client_id(args.client_id)
@@ -186,22 +280,54 @@ fn client_from_args(args: Args) -> Result<Client> {
          "true" => true,
          "false" => false,
          _ => panic!("Bad validation"),
+     })
+     .set_clean_session(match args.clean_session.as_str() {
+         "true" => true,
+         "false" => false,
+         _ => panic!("Bad validation"),
+     })
+     .set_protocol_version(match args.protocol_version.as_str() {
+         "3.1.1" => ProtocolVersion::V311,
+         "5" => ProtocolVersion::V5,
+         _ => panic!("Bad validation"),
      });
 
+    if let Some(t) = args.will_topic {
+        let mut lw = LastWill::new(t, args.will_message.as_bytes().to_vec());
+        lw.set_qos(int_to_qos(args.will_qos));
+        lw.set_retain(args.will_retain);
+        b.set_last_will(Some(lw));
+    }
+
+    #[cfg(feature = "websocket")]
+    {
+        if let Some(path) = args.websocket_path {
+            let mut wo = WebSocketOptions::new();
+            wo.set_path(path);
+            for h in args.websocket_header.iter() {
+                let mut parts = h.splitn(2, ':');
+                let name = parts.next().expect("at least one part").trim();
+                let value = parts.next()
+                    .ok_or_else(|| Error::from(format!("Bad WebSocket header '{}'", h)))?
+                    .trim();
+                wo.add_header(name.to_owned(), value.to_owned());
+            }
+            b.set_websocket(Some(wo));
+        }
+    }
+
     if let Some(s) = args.tls_server_ca_file {
-        let mut cc = rustls::ClientConfig::new();
-        let cert_bytes = std::fs::read(s)?;
-        let cert = rustls::internal::pemfile::certs(&mut Cursor::new(&cert_bytes[..]))
-            .map_err(|_| Error::from("Error parsing cert file"))?[0].clone();
-        cc.root_store.add(&cert)
-            .map_err(|e| Error::from_std_err(e))?;
-        b.set_tls_client_config(cc);
+        b.set_tls_ca_certs(load_certs_file(s)?);
     } else if args.tls_mozilla_root_cas {
         let mut cc = rustls::ClientConfig::new();
         cc.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
         b.set_tls_client_config(cc);
     }
 
+    if let (Some(cert), Some(key)) = (args.tls_client_cert, args.tls_client_key) {
+        b.set_tls_client_cert(load_certs_file(cert)?, load_private_key_file(key)?);
+    }
+
     b.build()
 }
 
@@ -2,31 +2,68 @@ use bytes::BytesMut;
 use crate::{
     client::{
         builder::ClientBuilder,
+        codec::{
+            self,
+            Envelope,
+        },
+        connector::Connector,
+        reconnect::ReconnectPolicy,
+        session_store::{
+            OutboundQueue,
+            OverflowPolicy,
+            SessionStore,
+        },
+        subscription::{
+            Router,
+            Subscription,
+            topic_matches,
+        },
         value_types::{
+            Ack,
+            ConnectionEvent,
+            DisconnectReason,
+            Endpoint,
+            EndpointPolicy,
             KeepAlive,
+            LastWill,
+            Properties,
+            ProtocolVersion,
             Publish,
             ReadResult,
+            ReasonCode,
             Subscribe,
             SubscribeResult,
             Unsubscribe,
         },
+        write_pids::{
+            PidGuard,
+            REPLAY_PID,
+            WritePids,
+        },
     },
     Error,
     Result,
     util::{
         AsyncStream,
-        FreePidList,
         TokioRuntime,
     }
 };
+#[cfg(feature = "websocket")]
+use crate::{
+    client::value_types::WebSocketOptions,
+    util::WebSocketStream,
+};
+use futures_core::Stream;
 use futures_util::{
     future::{
         FutureExt,
         pending,
     },
     select,
+    stream,
 };
 use log::{debug, error, info, trace};
+use rand::Rng;
 use mqttrs::{
     ConnectReturnCode,
     Packet,
@@ -34,28 +71,35 @@ use mqttrs::{
     QoS,
     QosPid,
     self,
+    SubscribeReturnCodes,
     SubscribeTopic,
 };
 #[cfg(feature = "tls")]
 use rustls;
 use std::{
     cmp::min,
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt,
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         Mutex,
+        Weak,
     },
+    time::SystemTime,
 };
 use tokio::{
     io::{
         AsyncReadExt,
         AsyncWriteExt,
     },
-    net::TcpStream,
     sync::{
-        mpsc,
+        broadcast,
+        mpsc::{
+            self,
+            error::TrySendError,
+        },
+        Notify,
         oneshot,
     },
     time::{
@@ -65,6 +109,7 @@ use tokio::{
         error::Elapsed,
         Instant,
         timeout,
+        timeout_at,
     },
 };
 #[cfg(feature = "tls")]
@@ -89,7 +134,9 @@ use tokio_rustls::{
 /// ```
 ///
 /// `Client` is expected to be `Send` (passable between threads), but not
-/// `Sync` (usable by multiple threads at the same time).
+/// `Sync` (usable by multiple threads at the same time). Use
+/// `Client::handle` to publish, subscribe and unsubscribe from several
+/// tasks at once.
 pub struct Client {
     /// Options configured for the client
     options: ClientOptions,
@@ -97,11 +144,17 @@ pub struct Client {
     /// Handle values to communicate with the IO task
     io_task_handle: Option<IoTaskHandle>,
 
-    /// Tracks which Pids (MQTT packet IDs) are in use.
-    ///
-    /// This field uses a Mutex for interior mutability so that
-    /// `Client` is `Send`. It's not expected to be `Sync`.
-    free_write_pids: Mutex<FreePidList>,
+    /// Tracks which Pids (MQTT packet IDs) are in use. Shared with
+    /// ClientHandle and IoTask.
+    write_pids: Arc<WritePids>,
+
+    /// Publishes waiting to be sent, if there is a session store.
+    /// Shared with ClientHandle.
+    outbound: Option<Arc<OutboundQueue>>,
+
+    /// Sender for connection events, shared with the IO task.
+    /// `Client::events` subscribes to it.
+    events_tx: broadcast::Sender<ConnectionEvent>,
 }
 
 impl fmt::Debug for Client {
@@ -116,8 +169,8 @@ impl fmt::Debug for Client {
 pub(crate) struct ClientOptions {
     // See ClientBuilder methods for per-field documentation.
 
-    pub(crate) host: String,
-    pub(crate) port: u16,
+    pub(crate) endpoints: Vec<Endpoint>,
+    pub(crate) endpoint_policy: EndpointPolicy,
     pub(crate) username: Option<String>,
     pub(crate) password: Option<Vec<u8>>,
     pub(crate) keep_alive: KeepAlive,
@@ -126,17 +179,30 @@ pub(crate) struct ClientOptions {
     pub(crate) packet_buffer_len: usize,
     pub(crate) max_packet_len: usize,
     pub(crate) operation_timeout: Duration,
+    pub(crate) publish_timeout: Duration,
+    pub(crate) connector: Arc<dyn Connector>,
     #[cfg(feature = "tls")]
     pub(crate) tls_client_config: Option<Arc<rustls::ClientConfig>>,
     pub(crate) automatic_connect: bool,
-    pub(crate) connect_retry_delay: Duration,
+    pub(crate) reconnect_policy: Arc<dyn ReconnectPolicy>,
+    pub(crate) clean_session: bool,
+    pub(crate) max_in_flight: u16,
+    pub(crate) manual_acks: bool,
+    pub(crate) session_store: Option<Arc<dyn SessionStore>>,
+    pub(crate) max_stored_publishes: usize,
+    pub(crate) session_store_overflow: OverflowPolicy,
+    pub(crate) last_will: Option<LastWill>,
+    pub(crate) protocol_version: ProtocolVersion,
+    pub(crate) connect_properties: Properties,
+    #[cfg(feature = "websocket")]
+    pub(crate) websocket: Option<WebSocketOptions>,
 }
 
 impl fmt::Debug for ClientOptions {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("ClientOptions")
-         .field("host", &self.host)
-         .field("port", &self.port)
+        let mut d = f.debug_struct("ClientOptions");
+        d.field("endpoints", &self.endpoints)
+         .field("endpoint_policy", &self.endpoint_policy)
          .field("username", &self.username)
          // Deliberately skipping password field here to
          // avoid accidentially leaking it
@@ -145,22 +211,75 @@ impl fmt::Debug for ClientOptions {
          .field("packet_buffer_len", &self.packet_buffer_len)
          .field("max_packet_len", &self.max_packet_len)
          .field("operation_timeout", &self.operation_timeout)
+         .field("publish_timeout", &self.publish_timeout)
+         .field("connector", &self.connector)
          .field("automatic_connect", &self.automatic_connect)
-         .field("connect_retry_delay", &self.connect_retry_delay)
-         .finish()
+         .field("reconnect_policy", &self.reconnect_policy)
+         .field("clean_session", &self.clean_session)
+         .field("max_in_flight", &self.max_in_flight)
+         .field("manual_acks", &self.manual_acks)
+         .field("session_store", &self.session_store)
+         .field("max_stored_publishes", &self.max_stored_publishes)
+         .field("session_store_overflow", &self.session_store_overflow)
+         .field("last_will", &self.last_will)
+         // Skipping connect_properties, which may hold authentication data.
+         .field("protocol_version", &self.protocol_version);
+        #[cfg(feature = "websocket")]
+        d.field("websocket", &self.websocket);
+        d.finish()
     }
 }
 
 /// The client side of the communication channels to an IO task.
 struct IoTaskHandle {
-    /// Sender to send IO requests to the IO task.
-    tx_io_requests: mpsc::Sender<IoRequest>,
+    /// Handle to send IO requests to the IO task.
+    handle: ClientHandle,
 
-    /// Receiver to receive Publish packets from the IO task.
-    rx_recv_published: mpsc::Receiver<Packet>,
+    /// Receiver to receive Publish packets from the IO task, or
+    /// `None` once `Client::messages` has taken it.
+    rx_recv_published: Option<mpsc::Receiver<Result<ReadResult>>>,
 
     /// Signal to the IO task to shutdown. Shared with IoTask.
-    halt: Arc<AtomicBool>,
+    halt: Arc<Halt>,
+
+    /// The session present flag from the last Connack. Shared with IoTask.
+    session_present: Arc<AtomicBool>,
+
+    /// The endpoint of the current connection. Shared with IoTask.
+    connected_endpoint: Arc<Mutex<Option<Endpoint>>>,
+}
+
+/// The most the client waits before reconnecting after losing an
+/// established connection.
+const RECONNECT_JITTER: Duration = Duration::from_secs(1);
+
+/// A signal to the IO task to shutdown, which also wakes it while it
+/// waits to reconnect.
+#[derive(Default)]
+struct Halt {
+    halted: AtomicBool,
+    notify: Notify,
+}
+
+impl Halt {
+    fn set(&self) {
+        self.halted.store(true, Ordering::SeqCst);
+        self.notify.notify_one();
+    }
+
+    fn is_set(&self) -> bool {
+        self.halted.load(Ordering::SeqCst)
+    }
+
+    /// Sleep for `delay`, or until the signal is set.
+    async fn sleep(&self, delay: Duration) {
+        let mut sleep_fut = Box::pin(sleep(delay).fuse());
+        let mut notified_fut = Box::pin(self.notify.notified().fuse());
+        select! {
+            _ = sleep_fut => (),
+            _ = notified_fut => (),
+        }
+    }
 }
 
 /// The state held by the IO task, a long-running tokio future. The IO
@@ -175,7 +294,7 @@ struct IoTask {
     rx_io_requests: mpsc::Receiver<IoRequest>,
 
     /// Sender to send Publish packets from the IO task.
-    tx_recv_published: mpsc::Sender<Packet>,
+    tx_recv_published: mpsc::Sender<Result<ReadResult>>,
 
     /// enum value describing the current state as disconnected or connected.
     state: IoTaskState,
@@ -184,8 +303,78 @@ struct IoTask {
     /// replayed after reconnecting.
     subscriptions: BTreeMap<String, QoS>,
 
+    /// Pids of QoS::ExactlyOnce publishes received from the broker
+    /// that are waiting for a Pubrel. A publish redelivered with one
+    /// of these Pids has already been passed to the Client.
+    qos2_received: BTreeSet<Pid>,
+
+    /// A map from response Pid to the IoRequest that initiated the
+    /// request that will be responded to.
+    ///
+    /// This outlives a single connection so that in-flight publishes
+    /// can be retransmitted after reconnecting.
+    pid_response_map: BTreeMap<Pid, IoRequest>,
+
+    /// Tracks which Pids (MQTT packet IDs) are in use. Shared with
+    /// Client and ClientHandle.
+    write_pids: Arc<WritePids>,
+
+    /// Pids of QoS::ExactlyOnce publishes the Client stopped waiting
+    /// for, for which the IO task sent a Pubrel itself after a late
+    /// Pubrec. The Pid is orphaned until the Pubcomp arrives.
+    orphaned_pubrels: BTreeSet<Pid>,
+
     /// Signal to the IO task to shutdown. Shared with IoTaskHandle.
-    halt: Arc<AtomicBool>,
+    halt: Arc<Halt>,
+
+    /// The session present flag from the last Connack. Shared with IoTaskHandle.
+    session_present: Arc<AtomicBool>,
+
+    /// Incremented for each connection, to tell which connection a
+    /// received message arrived on.
+    connection_id: u64,
+
+    /// Index into `options.endpoints` of the endpoint to connect to next.
+    endpoint_index: usize,
+
+    /// The number of connection attempts that have failed since the
+    /// last success or the last retry delay.
+    failed_connects: usize,
+
+    /// The endpoint of the current connection. Shared with IoTaskHandle.
+    connected_endpoint: Arc<Mutex<Option<Endpoint>>>,
+
+    /// The number of times in a row that every endpoint has failed to
+    /// connect, passed to the reconnect policy.
+    reconnect_attempts: u32,
+
+    /// The last delay returned by the reconnect policy.
+    reconnect_delay: Duration,
+
+    /// Set when the IO task gives up connecting. Shared with IoTaskHandle.
+    gave_up: Arc<Mutex<Option<GaveUp>>>,
+
+    /// Sender for connection events.
+    events_tx: broadcast::Sender<ConnectionEvent>,
+
+    /// Routes messages to `Subscription` streams. Shared with ClientHandle.
+    router: Arc<Mutex<Router>>,
+}
+
+/// Why the IO task gave up connecting.
+#[derive(Clone, Debug)]
+struct GaveUp {
+    attempts: u32,
+    last_error: String,
+}
+
+impl GaveUp {
+    fn to_error(&self) -> Error {
+        Error::ConnectAttemptsExhausted {
+            attempts: self.attempts,
+            last_error: self.last_error.clone(),
+        }
+    }
 }
 
 enum IoTaskState {
@@ -216,9 +405,8 @@ struct IoTaskConnected {
     /// The time the last Pingresp packet was read from `stream`.
     last_pingresp_time: Instant,
 
-    /// A map from response Pid to the IoRequest that initiated the
-    /// request that will be responded to.
-    pid_response_map: BTreeMap<Pid, IoRequest>,
+    /// MQTT 5 topic aliases set by the broker on this connection.
+    topic_aliases: BTreeMap<u16, String>,
 }
 
 /// An IO request from `Client` to the IO task.
@@ -235,10 +423,14 @@ struct IoRequest {
 #[derive(Debug)]
 enum IoType {
     /// A packet to write that expects no response.
-    WriteOnly { packet: Packet },
+    WriteOnly { packet: Envelope },
+
+    /// An acknowledgement to write if the connection the message
+    /// arrived on is still open.
+    Acknowledge { packet: Envelope, connection_id: u64 },
 
     /// A packet to write that expects a response with a certain `Pid`.
-    WriteAndResponse { packet: Packet, response_pid: Pid },
+    WriteAndResponse { packet: Envelope, response_pid: Pid },
 
     /// A request to shut down the TCP connection gracefully.
     ShutdownConnection,
@@ -247,7 +439,7 @@ enum IoType {
 /// The result of an IO request sent by the IO task, which may contain a packet.
 #[derive(Debug)]
 struct IoResult {
-    result: Result<Option<Packet>>,
+    result: Result<Option<Envelope>>,
 }
 
 impl Client {
@@ -257,10 +449,18 @@ impl Client {
     }
 
     pub(crate) fn new(opts: ClientOptions) -> Result<Client> {
+        let (events_tx, _) = broadcast::channel(opts.packet_buffer_len);
+        let write_pids = Arc::new(WritePids::new(opts.max_in_flight));
+        let outbound = opts.session_store.as_ref().map(|store| {
+            Arc::new(OutboundQueue::new(store.clone(), opts.max_stored_publishes,
+                                        opts.session_store_overflow))
+        });
         Ok(Client {
             options: opts,
             io_task_handle: None,
-            free_write_pids: Mutex::new(FreePidList::new()),
+            write_pids,
+            outbound,
+            events_tx,
         })
     }
 
@@ -274,14 +474,37 @@ impl Client {
         self.check_no_io_task()?;
         let (tx_io_requests, rx_io_requests) =
             mpsc::channel::<IoRequest>(self.options.packet_buffer_len);
-        // TODO: Change this to allow control messages, e.g. disconnected?
         let (tx_recv_published, rx_recv_published) =
-            mpsc::channel::<Packet>(self.options.packet_buffer_len);
-        let halt = Arc::new(AtomicBool::new(false));
+            mpsc::channel::<Result<ReadResult>>(self.options.packet_buffer_len);
+        let halt = Arc::new(Halt::default());
+        let session_present = Arc::new(AtomicBool::new(false));
+        let connected_endpoint = Arc::new(Mutex::new(None));
+        let gave_up = Arc::new(Mutex::new(None));
+        let router = Arc::new(Mutex::new(Router::default()));
+        let handle = ClientHandle {
+            inner: Arc::new(ClientHandleInner {
+                options: self.options.clone(),
+                tx_io_requests,
+                write_pids: self.write_pids.clone(),
+                outbound: self.outbound.clone(),
+                gave_up: gave_up.clone(),
+                router: router.clone(),
+            }),
+        };
+        if let Some(ref outbound) = self.outbound {
+            self.options.runtime.spawn(send_stored_publishes(
+                outbound.clone(),
+                Arc::downgrade(&handle.inner),
+                halt.clone(),
+                connected_endpoint.clone(),
+                self.events_tx.subscribe()));
+        }
         self.io_task_handle = Some(IoTaskHandle {
-            tx_io_requests,
-            rx_recv_published,
+            handle,
+            rx_recv_published: Some(rx_recv_published),
             halt: halt.clone(),
+            session_present: session_present.clone(),
+            connected_endpoint: connected_endpoint.clone(),
         });
         let io = IoTask {
             options: self.options.clone(),
@@ -289,7 +512,21 @@ impl Client {
             tx_recv_published,
             state: IoTaskState::Disconnected,
             subscriptions: BTreeMap::new(),
+            qos2_received: BTreeSet::new(),
+            pid_response_map: BTreeMap::new(),
+            write_pids: self.write_pids.clone(),
+            orphaned_pubrels: BTreeSet::new(),
             halt: halt,
+            session_present,
+            connection_id: 0,
+            endpoint_index: 0,
+            failed_connects: 0,
+            connected_endpoint,
+            reconnect_attempts: 0,
+            reconnect_delay: Duration::from_secs(0),
+            gave_up,
+            events_tx: self.events_tx.clone(),
+            router,
         };
         self.options.runtime.spawn(io.run());
         Ok(())
@@ -300,278 +537,714 @@ impl Client {
     /// Note that this method takes `&self`. This means a caller can
     /// create several publish futures to publish several payloads of
     /// data simultaneously without waiting for responses.
+    ///
+    /// See `ClientHandle::publish`.
     pub async fn publish(&self, p: &Publish) -> Result<()> {
-        let qos = p.qos();
-        if qos == QoS::ExactlyOnce {
-            return Err("QoS::ExactlyOnce is not supported".into());
+        self.check_io_task()?.handle.publish(p).await
+    }
+
+    /// Subscribe to some topics.`read_subscriptions` will return
+    /// data for them.
+    pub async fn subscribe(&mut self, s: Subscribe) -> Result<SubscribeResult> {
+        self.check_io_task()?.handle.subscribe(s).await
+    }
+
+    /// Unsubscribe from some topics. `read_subscriptions` will no
+    /// longer return data for them.
+    ///
+    /// See `ClientHandle::unsubscribe`.
+    pub async fn unsubscribe(&mut self, u: Unsubscribe) -> Result<()> {
+        self.check_io_task()?.handle.unsubscribe(u).await
+    }
+
+    /// Subscribe to some topics, and return a stream of the messages
+    /// for them. See `ClientHandle::subscribe_stream`.
+    pub async fn subscribe_stream(&self, s: Subscribe) -> Result<Subscription> {
+        self.check_io_task()?.handle.subscribe_stream(s).await
+    }
+
+    /// Returns a cheaply cloneable handle to publish, subscribe and
+    /// unsubscribe from several tasks at once.
+    ///
+    /// Handles share this client's connection, and stop working when
+    /// it disconnects. They keep the IO task running if the `Client`
+    /// is dropped without calling `disconnect`.
+    pub fn handle(&self) -> Result<ClientHandle> {
+        Ok(self.check_io_task()?.handle.clone())
+    }
+
+    /// Wait for the next Publish packet for one of this Client's subscriptions.
+    ///
+    /// Messages that match a `Subscription` stream are returned by
+    /// that stream instead.
+    ///
+    /// QoS 1 and 2 messages are acknowledged before they are returned,
+    /// or with `ClientBuilder::set_manual_acks` when the application
+    /// calls `ReadResult::ack`.
+    ///
+    /// With MQTT 5, if the server closes the connection with a
+    /// Disconnect packet this returns `Error::ServerDisconnected`. The
+    /// client then reconnects as usual if automatic connect is enabled.
+    pub async fn read_subscriptions(&mut self) -> Result<ReadResult> {
+        let h = self.check_io_task_mut()?;
+        let rx = match h.rx_recv_published {
+            Some(ref mut rx) => rx,
+            None => return Err(Error::InvalidState(
+                "Messages are being read by the Client::messages stream".to_owned())),
+        };
+        let mut rr = match rx.recv().await {
+            Some(r) => r?,
+            None => {
+                // Sender closed.
+                let e = h.handle.gave_up_error().unwrap_or(Error::Disconnected);
+                self.io_task_handle = None;
+                return Err(e);
+            }
+        };
+        h.handle.accept(&mut rr).await?;
+        Ok(rr)
+    }
+
+    /// Returns a stream of the Publish packets for this Client's
+    /// subscriptions, as an alternative to `read_subscriptions`.
+    ///
+    /// Messages are acknowledged as the stream returns them, unless
+    /// the client was built with `ClientBuilder::set_manual_acks`. Errors,
+    /// e.g. `Error::ServerDisconnected`, are returned as items and the
+    /// stream continues. The stream ends after `disconnect`, or after
+    /// returning `Error::ConnectAttemptsExhausted`.
+    ///
+    /// This can only be called once per connection, and
+    /// `read_subscriptions` returns an error afterwards.
+    pub fn messages(&mut self)
+        -> Result<impl Stream<Item = Result<ReadResult>> + Send + Unpin + 'static>
+    {
+        let h = self.check_io_task_mut()?;
+        let rx = match h.rx_recv_published.take() {
+            Some(rx) => rx,
+            None => return Err(Error::InvalidState("Client::messages was already called".to_owned())),
+        };
+        let state = Some((rx, h.handle.clone()));
+        Ok(Box::pin(stream::unfold(state, |state| async move {
+            let (mut rx, handle) = state?;
+            let mut rr = match rx.recv().await {
+                Some(Ok(rr)) => rr,
+                Some(Err(e)) => return Some((Err(e), Some((rx, handle)))),
+                // Sender closed, so end the stream, with an error if
+                // the IO task gave up connecting.
+                None => return handle.gave_up_error().map(|e| (Err(e), None)),
+            };
+            let res = handle.accept(&mut rr).await.map(|()| rr);
+            Some((res, Some((rx, handle))))
+        })))
+    }
+
+    /// Returns the session present flag from the broker's response to
+    /// the most recent connection.
+    ///
+    /// This is true when the broker resumed a persistent session, see
+    /// `ClientBuilder::set_clean_session`. Returns false until the first
+    /// connection succeeds.
+    pub fn session_present(&self) -> Result<bool> {
+        let h = self.check_io_task()?;
+        Ok(h.session_present.load(Ordering::SeqCst))
+    }
+
+    /// Returns a stream of events as the client connects, disconnects
+    /// and reconnects.
+    ///
+    /// Each stream receives the events sent after it is created, so
+    /// call this before `connect` to see the first connection. A
+    /// stream that falls more than `packet_buffer_len` events behind
+    /// skips the oldest events.
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> + Send + Unpin + 'static {
+        Box::pin(stream::unfold(self.events_tx.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(n)) =>
+                        debug!("Skipped {} connection events", n),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+
+    /// Returns the endpoint the client is currently connected to, or
+    /// `None` while it is disconnected.
+    pub fn connected_endpoint(&self) -> Result<Option<Endpoint>> {
+        let h = self.check_io_task()?;
+        Ok(h.connected_endpoint.lock().expect("connected_endpoint lock").clone())
+    }
+
+    /// Gracefully close the connection to the server.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        debug!("Disconnecting");
+        // Without a connection the IO task may be waiting to reconnect,
+        // and there is nothing to send Disconnect on.
+        if self.connected_endpoint()?.is_some() {
+            let p = Packet::Disconnect;
+            let res = timeout(self.options.operation_timeout,
+                              self.write_only_packet(p.into())).await;
+            if let Err(Elapsed { .. }) = res {
+                return Err(Error::Timeout {
+                    operation: "waiting for Disconnect to send",
+                    after: self.options.operation_timeout,
+                });
+            }
+            res.expect("No timeout")?;
+        }
+        self.shutdown().await?;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> Result <()> {
+        let c = self.check_io_task()?;
+        c.halt.set();
+        if let Err(e) = self.write_request(IoType::ShutdownConnection).await {
+            // The IO task may have seen `halt` and stopped first.
+            debug!("IO task stopped before ShutdownConnection: {}", e);
+        }
+        self.io_task_handle = None;
+        Ok(())
+    }
+
+    async fn write_only_packet(&self, p: Envelope) -> Result<()> {
+        self.check_io_task()?.handle.write_only_packet(p).await
+    }
+
+    async fn write_request(&self, io_type: IoType) -> Result<Option<Envelope>> {
+        self.check_io_task()?.handle.write_request(io_type).await
+    }
+
+    fn check_io_task_mut(&mut self) -> Result<&mut IoTaskHandle> {
+        match self.io_task_handle {
+            Some(ref mut h) => Ok(h),
+            None => Err(Error::InvalidState("No IO task, did you call connect?".to_owned())),
+        }
+    }
+
+    fn check_io_task(&self) -> Result<&IoTaskHandle> {
+        match self.io_task_handle {
+            Some(ref h) => Ok(h),
+            None => Err(Error::InvalidState("No IO task, did you call connect?".to_owned())),
+        }
+    }
+
+    fn check_no_io_task(&self) -> Result<()> {
+        match self.io_task_handle {
+            Some(_) => Err(Error::InvalidState("Already spawned IO task".to_owned())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A cheaply cloneable handle to publish, subscribe and unsubscribe
+/// using a connected `Client`, see `Client::handle`.
+///
+/// `ClientHandle` is `Send` and `Sync` and its methods take `&self`,
+/// so clones can be used from many tasks at once. Requests from all
+/// clones share the client's connection. Read messages for
+/// subscriptions with `Client::read_subscriptions`.
+#[derive(Clone)]
+pub struct ClientHandle {
+    inner: Arc<ClientHandleInner>,
+}
+
+struct ClientHandleInner {
+    /// Options configured for the client.
+    options: ClientOptions,
+
+    /// Sender to send IO requests to the IO task.
+    tx_io_requests: mpsc::Sender<IoRequest>,
+
+    /// Tracks which Pids (MQTT packet IDs) are in use. Shared with
+    /// Client and IoTask.
+    write_pids: Arc<WritePids>,
+
+    /// Publishes waiting to be sent, if there is a session store.
+    /// Shared with Client.
+    outbound: Option<Arc<OutboundQueue>>,
+
+    /// Set when the IO task gives up connecting. Shared with IoTask.
+    gave_up: Arc<Mutex<Option<GaveUp>>>,
+
+    /// Routes messages to `Subscription` streams. Shared with IoTask.
+    router: Arc<Mutex<Router>>,
+}
+
+impl fmt::Debug for ClientHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientHandle")
+         .field("options", &self.inner.options)
+         .finish()
+    }
+}
+
+impl ClientHandle {
+    /// Publish some data on a topic.
+    ///
+    /// With `QoS::AtLeastOnce` or `QoS::ExactlyOnce` the publish is
+    /// retransmitted after a reconnect until the broker acknowledges
+    /// it or the publish timeout passes. With `QoS::ExactlyOnce` this
+    /// waits for the whole Publish / Pubrec / Pubrel / Pubcomp exchange
+    /// to complete.
+    ///
+    /// With MQTT 5 an acknowledgement with a failure reason code
+    /// returns `Error::ReasonCode`.
+    ///
+    /// With a session store, see `ClientBuilder::set_session_store`,
+    /// QoS 1 and 2 publishes return once they are stored instead.
+    pub async fn publish(&self, p: &Publish) -> Result<()> {
+        match self.inner.outbound {
+            Some(ref outbound) if p.qos() != QoS::AtMostOnce =>
+                outbound.push(p, self.inner.options.operation_timeout).await,
+            _ => self.send_publish(p).await,
         }
-        let p2 = Packet::Publish(mqttrs::Publish {
-            dup: false, // TODO.
-            qospid: match qos {
-                QoS::AtMostOnce => QosPid::AtMostOnce,
-                QoS::AtLeastOnce => QosPid::AtLeastOnce(self.alloc_write_pid()?),
-                QoS::ExactlyOnce => panic!("Not reached"),
+    }
+
+    /// Send a publish to the broker and wait for it to complete.
+    async fn send_publish(&self, p: &Publish) -> Result<()> {
+        let qos = p.qos();
+        let pid = match qos {
+            QoS::AtMostOnce => None,
+            QoS::AtLeastOnce | QoS::ExactlyOnce => Some(self.alloc_write_pid().await?),
+        };
+        let mut p2 = Envelope::from(Packet::Publish(mqttrs::Publish {
+            // The IO task sets dup when it retransmits after reconnecting
+            // to a resumed session.
+            dup: false,
+            qospid: match (qos, &pid) {
+                (QoS::AtLeastOnce, Some(pid)) => QosPid::AtLeastOnce(pid.pid()),
+                (QoS::ExactlyOnce, Some(pid)) => QosPid::ExactlyOnce(pid.pid()),
+                _ => QosPid::AtMostOnce,
             },
             retain: p.retain(),
             topic_name: p.topic().to_owned(),
             payload: p.payload().to_owned(),
-        });
+        }));
+        p2.properties = p.properties().clone();
         match qos {
             QoS::AtMostOnce => {
-                let res = timeout(self.options.operation_timeout,
-                                  self.write_only_packet(&p2)).await;
+                let res = timeout(self.inner.options.operation_timeout,
+                                  self.write_only_packet(p2)).await;
                 if let Err(Elapsed { .. }) = res {
-                    return Err(format!("Timeout writing publish after {}ms",
-                                       self.options.operation_timeout.as_millis()).into());
+                    return Err(Error::Timeout {
+                        operation: "writing publish",
+                        after: self.inner.options.operation_timeout,
+                    });
                 }
                 res.expect("No timeout")?;
             }
             QoS::AtLeastOnce => {
-                let res = timeout(self.options.operation_timeout,
-                                  self.write_response_packet(&p2)).await;
+                let pid = pid.expect("Pid for QoS::AtLeastOnce");
+                let res = timeout(self.inner.options.publish_timeout,
+                                  self.write_response_packet(p2)).await;
                 if let Err(Elapsed { .. }) = res {
-                    // We report this but can't really deal with it properly.
-                    // The protocol says we can't re-use the packet ID so we have to leak it
-                    // and potentially run out of packet IDs.
-                    return Err(format!("Timeout waiting for Puback after {}ms",
-                                       self.options.operation_timeout.as_millis()).into());
+                    // The protocol says we can't re-use the packet ID
+                    // yet, so dropping it orphans it until a late
+                    // Puback or a clean session.
+                    return Err(Error::Timeout {
+                        operation: "waiting for Puback",
+                        after: self.inner.options.publish_timeout,
+                    });
                 }
-                let res = res.expect("No timeout")?;
-                match res {
-                    Packet::Puback(pid) => self.free_write_pid(pid)?,
+                let (res, pid) = response_or_free(res.expect("No timeout"), pid)?;
+                match res.packet {
+                    Packet::Puback(_) => {
+                        pid.free()?;
+                        check_reason_codes(&res)?;
+                    },
                     _ => error!("Bad packet response for publish: {:#?}", res),
                 }
             },
-            QoS::ExactlyOnce => panic!("Not reached"),
+            QoS::ExactlyOnce => {
+                let pid_guard = pid.expect("Pid for QoS::ExactlyOnce");
+                let deadline = Instant::now() + self.inner.options.publish_timeout;
+                let res = timeout_at(deadline, self.write_response_packet(p2)).await;
+                if let Err(Elapsed { .. }) = res {
+                    // As for QoS::AtLeastOnce the packet ID is orphaned.
+                    return Err(Error::Timeout {
+                        operation: "waiting for Pubrec",
+                        after: self.inner.options.publish_timeout,
+                    });
+                }
+                let (res, pid_guard) = response_or_free(res.expect("No timeout"), pid_guard)?;
+                let pid = match res.packet {
+                    Packet::Pubrec(pid) => pid,
+                    _ => return Err(unexpected_packet("Pubrec", &res)),
+                };
+                if res.failure().is_some() {
+                    // The broker refused the message, so there is no Pubrel.
+                    pid_guard.free()?;
+                    return check_reason_codes(&res);
+                }
+
+                // The broker now owns the message. The Pid stays in use
+                // until the Pubcomp arrives.
+                let res = timeout_at(deadline,
+                                     self.write_response_packet(Packet::Pubrel(pid).into())).await;
+                if let Err(Elapsed { .. }) = res {
+                    return Err(Error::Timeout {
+                        operation: "waiting for Pubcomp",
+                        after: self.inner.options.publish_timeout,
+                    });
+                }
+                let res = res.expect("No timeout")?;
+                match res.packet {
+                    Packet::Pubcomp(comp_pid) if comp_pid == pid => {
+                        pid_guard.free()?;
+                        check_reason_codes(&res)?;
+                    },
+                    _ => {
+                        return Err(unexpected_packet(format!("Pubcomp(Pid={:?})", pid),
+                                                     &res));
+                    }
+                }
+            },
         };
         Ok(())
     }
 
-    /// Subscribe to some topics.`read_subscriptions` will return
-    /// data for them.
-    pub async fn subscribe(&mut self, s: Subscribe) -> Result<SubscribeResult> {
-        let pid = self.alloc_write_pid()?;
-        // TODO: Support subscribe to qos == ExactlyOnce.
-        if s.topics().iter().any(|t| t.qos == QoS::ExactlyOnce) {
-            return Err("Qos::ExactlyOnce is not supported right now".into())
-        }
+    /// Subscribe to some topics. `Client::read_subscriptions` will
+    /// return data for them.
+    pub async fn subscribe(&self, s: Subscribe) -> Result<SubscribeResult> {
+        let pid_guard = self.alloc_write_pid().await?;
+        let pid = pid_guard.pid();
         let p = Packet::Subscribe(mqttrs::Subscribe {
             pid: pid,
             topics: s.topics().to_owned(),
         });
-        let res = timeout(self.options.operation_timeout, self.write_response_packet(&p)).await;
+        let res = timeout(self.inner.options.operation_timeout,
+                          self.write_response_packet(p.into())).await;
         if let Err(Elapsed { .. }) = res {
-            // We report this but can't really deal with it properly.
-            // The protocol says we can't re-use the packet ID so we have to leak it
-            // and potentially run out of packet IDs.
-            return Err(format!("Timeout waiting for Suback after {}ms",
-                               self.options.operation_timeout.as_millis()).into());
+            // The protocol says we can't re-use the packet ID yet, so
+            // dropping it orphans it until a late Suback or a clean
+            // session.
+            return Err(Error::Timeout {
+                operation: "waiting for Suback",
+                after: self.inner.options.operation_timeout,
+            });
         }
-        let res = res.expect("No timeout")?;
-        match res {
+        let (res, pid_guard) = response_or_free(res.expect("No timeout"), pid_guard)?;
+        match res.packet {
             Packet::Suback(mqttrs::Suback {
                 pid: suback_pid,
                 return_codes: rcs,
             }) if suback_pid == pid => {
-                self.free_write_pid(pid)?;
+                pid_guard.free()?;
                 Ok(SubscribeResult {
-                    return_codes: rcs
+                    reason_codes: suback_reason_codes(&rcs, res.reason_codes),
+                    return_codes: rcs,
+                    properties: res.properties,
                 })
             },
-            _ => {
-                return Err(format!("Unexpected packet waiting for Suback(Pid={:?}): {:#?}",
-                                   pid, res)
-                           .into());
-            }
+            _ => Err(unexpected_packet(format!("Suback(Pid={:?})", pid), &res)),
         }
     }
 
-    /// Unsubscribe from some topics. `read_subscriptions` will no
-    /// longer return data for them.
-    pub async fn unsubscribe(&mut self, u: Unsubscribe) -> Result<()> {
-        let pid = self.alloc_write_pid()?;
+    /// Subscribe to some topics, and return a stream of the messages
+    /// whose topics match them.
+    ///
+    /// Messages for the stream are acknowledged when they are queued
+    /// for it, rather than when they are read, unless the client was
+    /// built with `ClientBuilder::set_manual_acks`. A stream queues up
+    /// to `packet_buffer_len` messages. When it is full, further QoS 0
+    /// messages for it are dropped, and QoS 1 and 2 messages are not
+    /// acknowledged so the broker sends them again later, which may
+    /// repeat them on other streams. Dropping the
+    /// `Subscription` unsubscribes from the topic filters that no
+    /// other `Subscription` uses, so don't also subscribe to them
+    /// with `subscribe`.
+    ///
+    /// Returns `Ok` even if the broker refused some topics, check
+    /// `Subscription::result`.
+    pub async fn subscribe_stream(&self, s: Subscribe) -> Result<Subscription> {
+        let filters = s.topics().iter().map(|t| t.topic_path.clone()).collect();
+        let (tx, rx) = mpsc::channel(self.inner.options.packet_buffer_len);
+        // Add the route first, so it receives any retained messages.
+        let id = self.inner.router.lock().expect("router lock").add(filters, tx);
+        match self.subscribe(s).await {
+            Ok(res) => Ok(Subscription::new(res, rx, id, self.clone())),
+            Err(e) => {
+                self.inner.router.lock().expect("router lock").remove(id);
+                Err(e)
+            },
+        }
+    }
+
+    /// Unsubscribe from some topics. `Client::read_subscriptions`
+    /// will no longer return data for them.
+    ///
+    /// With MQTT 5 a failure reason code for any topic returns
+    /// `Error::ReasonCode`.
+    pub async fn unsubscribe(&self, u: Unsubscribe) -> Result<()> {
+        let pid_guard = self.alloc_write_pid().await?;
+        let pid = pid_guard.pid();
         let p = Packet::Unsubscribe(mqttrs::Unsubscribe {
             pid: pid,
             topics: u.topics().iter().map(|ut| ut.topic_name().to_owned())
                      .collect::<Vec<String>>(),
         });
-        let res = timeout(self.options.operation_timeout, self.write_response_packet(&p)).await;
+        let res = timeout(self.inner.options.operation_timeout,
+                          self.write_response_packet(p.into())).await;
         if let Err(Elapsed { .. }) = res {
-            // We report this but can't really deal with it properly.
-            // The protocol says we can't re-use the packet ID so we have to leak it
-            // and potentially run out of packet IDs.
-            return Err(format!("Timeout waiting for Unsuback after {}ms",
-                               self.options.operation_timeout.as_millis()).into());
+            // As for subscribe the packet ID is orphaned.
+            return Err(Error::Timeout {
+                operation: "waiting for Unsuback",
+                after: self.inner.options.operation_timeout,
+            });
         }
-        let res = res.expect("No timeout")?;
-        match res {
+        let (res, pid_guard) = response_or_free(res.expect("No timeout"), pid_guard)?;
+        match res.packet {
             Packet::Unsuback(ack_pid)
             if ack_pid == pid => {
-                self.free_write_pid(pid)?;
-                Ok(())
+                pid_guard.free()?;
+                check_reason_codes(&res)
             },
-            _ => {
-                return Err(format!("Unexpected packet waiting for Unsuback(Pid={:?}): {:#?}",
-                                   pid, res)
-                           .into());
-            }
+            _ => Err(unexpected_packet(format!("Unsuback(Pid={:?})", pid), &res)),
         }
     }
 
-    /// Wait for the next Publish packet for one of this Client's subscriptions.
-    pub async fn read_subscriptions(&mut self) -> Result<ReadResult> {
-        let h = self.check_io_task_mut()?;
-        let r = match h.rx_recv_published.recv().await {
-            Some(r) => r,
-            None => {
-                // Sender closed.
-                self.io_task_handle = None;
-                return Err(Error::Disconnected);
-            }
-        };
-        match r {
-            Packet::Publish(p) => {
-                match p.qospid {
-                    QosPid::AtMostOnce => (),
-                    QosPid::AtLeastOnce(pid) => {
-                        self.write_only_packet(&Packet::Puback(pid)).await?;
-                    },
-                    QosPid::ExactlyOnce(_) => {
-                        error!("Received publish with unimplemented QoS: ExactlyOnce");
-                    }
-                }
-                let rr = ReadResult {
-                    topic: p.topic_name,
-                    payload: p.payload,
-                };
-                Ok(rr)
-            },
-            _ => {
-                return Err(format!("Unexpected packet waiting for read: {:#?}", r).into());
-            }
-        }
+    async fn alloc_write_pid(&self) -> Result<PidGuard> {
+        self.inner.write_pids.alloc(self.inner.options.operation_timeout).await
     }
 
-    /// Gracefully close the connection to the server.
-    pub async fn disconnect(&mut self) -> Result<()> {
-        self.check_io_task()?;
-        debug!("Disconnecting");
-        let p = Packet::Disconnect;
-        let res = timeout(self.options.operation_timeout,
-                          self.write_only_packet(&p)).await;
-        if let Err(Elapsed { .. }) = res {
-            return Err(format!("Timeout waiting for Disconnect to send after {}ms",
-                               self.options.operation_timeout.as_millis()).into());
-        }
-        res.expect("No timeout")?;
-        self.shutdown().await?;
-        Ok(())
-    }
-
-    fn alloc_write_pid(&self) -> Result<Pid> {
-        match self.free_write_pids.lock().expect("not poisoned").alloc() {
-            Some(pid) => Ok(Pid::try_from(pid).expect("Non-zero Pid")),
-            None => Err(Error::from("No free Pids")),
-        }
-    }
-
-    fn free_write_pid(&self, p: Pid) -> Result<()> {
-        match self.free_write_pids.lock().expect("not poisoned").free(p.get()) {
-            true => Err(Error::from("Pid was already free")),
-            false => Ok(())
-        }
-    }
-
-    async fn shutdown(&mut self) -> Result <()> {
-        let c = self.check_io_task()?;
-        c.halt.store(true, Ordering::SeqCst);
-        self.write_request(IoType::ShutdownConnection).await?;
-        self.io_task_handle = None;
-        Ok(())
-    }
-
-    async fn write_only_packet(&self, p: &Packet) -> Result<()> {
-        self.write_request(IoType::WriteOnly { packet: p.clone(), })
+    async fn write_only_packet(&self, p: Envelope) -> Result<()> {
+        self.write_request(IoType::WriteOnly { packet: p, })
             .await.map(|_v| ())
     }
 
-    async fn write_response_packet(&self, p: &Packet) -> Result<Packet> {
+    async fn write_response_packet(&self, p: Envelope) -> Result<Envelope> {
         let io_type = IoType::WriteAndResponse {
-            packet: p.clone(),
-            response_pid: packet_pid(p).expect("packet_pid"),
+            response_pid: packet_pid(&p.packet).expect("packet_pid"),
+            packet: p,
         };
         self.write_request(io_type)
             .await.map(|v| v.expect("return packet"))
     }
 
-    async fn write_request(&self, io_type: IoType) -> Result<Option<Packet>> {
+    async fn write_request(&self, io_type: IoType) -> Result<Option<Envelope>> {
         // NB: Some duplication in IoTask::replay_subscriptions.
 
-        let c = self.check_io_task()?;
         let (tx, rx) = oneshot::channel::<IoResult>();
         let req = IoRequest {
             tx_result: Some(tx),
             io_type: io_type,
         };
-        c.tx_io_requests.clone().send(req).await
-            .map_err(|e| Error::from_std_err(e))?;
+        // The IO task has stopped if either channel is closed.
+        self.inner.tx_io_requests.send(req).await
+            .map_err(|_| self.gave_up_error().unwrap_or(Error::Disconnected))?;
         // TODO: Add a timeout?
         let res = rx.await
-            .map_err(|e| Error::from_std_err(e))?;
+            .map_err(|_| self.gave_up_error().unwrap_or(Error::Disconnected))?;
         res.result
     }
 
-    fn check_io_task_mut(&mut self) -> Result<&mut IoTaskHandle> {
-        match self.io_task_handle {
-            Some(ref mut h) => Ok(h),
-            None => Err("No IO task, did you call connect?".into()),
+    /// Acknowledge a message from `read_subscriptions` or `messages`,
+    /// or with manual acks give it an `Ack` token instead.
+    async fn accept(&self, rr: &mut ReadResult) -> Result<()> {
+        if self.inner.options.manual_acks {
+            self.attach_ack(rr);
+            return Ok(());
         }
+        self.acknowledge(rr.qospid, rr.connection_id).await
+    }
+
+    /// Give a QoS 1 or 2 message an `Ack` token, with manual acks.
+    pub(crate) fn attach_ack(&self, rr: &mut ReadResult) {
+        if self.inner.options.manual_acks && rr.qospid != QosPid::AtMostOnce {
+            rr.ack = Some(Ack::new(self.clone(), rr));
+        }
+    }
+
+    /// Send the acknowledgement for a message received on the
+    /// connection `connection_id`, unless the client has reconnected
+    /// since.
+    pub(crate) async fn acknowledge(&self, qospid: QosPid, connection_id: u64) -> Result<()> {
+        let packet = match qospid {
+            QosPid::AtMostOnce => return Ok(()),
+            QosPid::AtLeastOnce(pid) => Packet::Puback(pid),
+            // The IO task tracks the Pid until the broker sends
+            // Pubrel, and answers that with Pubcomp.
+            QosPid::ExactlyOnce(pid) => Packet::Pubrec(pid),
+        };
+        self.write_request(IoType::Acknowledge { packet: packet.into(), connection_id })
+            .await.map(|_v| ())
+    }
+
+    pub(crate) fn router(&self) -> &Mutex<Router> {
+        &self.inner.router
+    }
+
+    pub(crate) fn runtime(&self) -> TokioRuntime {
+        self.inner.options.runtime.clone()
+    }
+
+    /// Returns `Error::ConnectAttemptsExhausted` if the IO task gave up connecting.
+    fn gave_up_error(&self) -> Option<Error> {
+        self.inner.gave_up.lock().expect("gave_up lock").as_ref().map(GaveUp::to_error)
     }
+}
 
-    fn check_io_task(&self) -> Result<&IoTaskHandle> {
-        match self.io_task_handle {
-            Some(ref h) => Ok(h),
-            None => Err("No IO task, did you call connect?".into()),
+/// Send publishes from the session store in order while the client
+/// is connected, until the client shuts down or is dropped.
+///
+/// After an error other than a reason code the publish is sent again
+/// with a new Pid once the connection changes or a second passes.
+async fn send_stored_publishes(
+    outbound: Arc<OutboundQueue>,
+    handle: Weak<ClientHandleInner>,
+    halt: Arc<Halt>,
+    connected_endpoint: Arc<Mutex<Option<Endpoint>>>,
+    mut events: broadcast::Receiver<ConnectionEvent>,
+) {
+    loop {
+        if halt.is_set() {
+            return;
+        }
+        let mut retry = false;
+        let handle = match handle.upgrade() {
+            Some(inner) => ClientHandle { inner },
+            None => return,
+        };
+        while connected_endpoint.lock().expect("connected_endpoint lock").is_some() {
+            let (id, p) = match outbound.front() {
+                Ok(Some(front)) => front,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Error reading the session store: {}", e);
+                    break;
+                },
+            };
+            match handle.send_publish(&p).await {
+                Ok(()) => (),
+                // The broker refused it, so sending it again won't help.
+                Err(e @ Error::ReasonCode { .. }) =>
+                    debug!("Stored publish to '{}' refused: {}", p.topic(), e),
+                Err(e) => {
+                    debug!("Error sending stored publish to '{}': {}", p.topic(), e);
+                    retry = true;
+                    break;
+                },
+            }
+            if let Err(e) = outbound.remove(id) {
+                error!("Error removing from the session store: {}", e);
+                retry = true;
+                break;
+            }
+        }
+        drop(handle);
+        if !outbound.wait(&mut events, retry).await {
+            return;
         }
     }
+}
 
-    fn check_no_io_task(&self) -> Result<()> {
-        match self.io_task_handle {
-            Some(_) => Err("Already spawned IO task".into()),
-            None => Ok(()),
+/// Start network connection to the server.
+async fn connect_stream(opts: &ClientOptions, ep: &Endpoint) -> Result<AsyncStream> {
+    let stream = connect_transport(opts, ep).await?;
+
+    #[cfg(feature = "websocket")]
+    {
+        if let Some(ref ws) = opts.websocket {
+            #[cfg(feature = "tls")]
+            let scheme = if tls_client_config(opts, ep).is_some() { "wss" } else { "ws" };
+            #[cfg(not(feature = "tls"))]
+            let scheme = "ws";
+            let host = if ep.host().contains(':') {
+                // IPv6 address literal
+                format!("[{}]", ep.host())
+            } else {
+                ep.host().to_owned()
+            };
+            let url = format!("{}://{}:{}{}", scheme, host, ep.port(), ws.path());
+            debug!("Starting WebSocket handshake with {}", url);
+            let ws_stream = WebSocketStream::connect(stream, url, ws).await?;
+            return Ok(AsyncStream::WebSocket(Box::new(ws_stream)));
         }
     }
+
+    Ok(stream)
 }
 
-/// Start network connection to the server.
-async fn connect_stream(opts: &ClientOptions) -> Result<AsyncStream> {
-    debug!("Connecting to {}:{}", opts.host, opts.port);
+/// Open the connection with the configured connector, and the TLS
+/// session if configured.
+async fn connect_transport(opts: &ClientOptions, ep: &Endpoint) -> Result<AsyncStream> {
+    debug!("Connecting to {}:{}", ep.host(), ep.port());
     #[cfg(feature = "tls")]
-    match opts.tls_client_config {
-        Some(ref c) => {
+    match tls_client_config(opts, ep) {
+        Some(c) => {
             let connector = TlsConnector::from(c.clone());
-            let domain = DNSNameRef::try_from_ascii_str(&*opts.host)
-                .map_err(|e| Error::from_std_err(e))?;
-            let tcp = TcpStream::connect((&*opts.host, opts.port)).await?;
-            let conn = connector.connect(domain, tcp).await?;
+            let domain = DNSNameRef::try_from_ascii_str(ep.host())
+                .map_err(|e| Error::Tls(Box::new(e)))?;
+            let transport = opts.connector.connect(ep).await?;
+            let conn = connector.connect(domain, transport).await
+                .map_err(|e| Error::Tls(Box::new(e)))?;
             Ok(AsyncStream::TlsStream(conn))
         },
         None => {
-            let tcp = TcpStream::connect((&*opts.host, opts.port)).await?;
-            Ok(AsyncStream::TcpStream(tcp))
+            let transport = opts.connector.connect(ep).await?;
+            Ok(AsyncStream::Transport(transport))
         }
     }
 
     #[cfg(not(feature = "tls"))]
     {
-        let tcp = TcpStream::connect((&*opts.host, opts.port)).await?;
-        Ok(AsyncStream::TcpStream(tcp))
+        let transport = opts.connector.connect(ep).await?;
+        Ok(AsyncStream::Transport(transport))
+    }
+}
+
+/// The TLS config for an endpoint: its own, or else the client's.
+#[cfg(feature = "tls")]
+fn tls_client_config<'a>(opts: &'a ClientOptions, ep: &'a Endpoint)
+                         -> Option<&'a Arc<rustls::ClientConfig>> {
+    ep.tls_client_config().or(opts.tls_client_config.as_ref())
+}
+
+/// An error for a packet the client did not expect.
+fn unexpected_packet<S: Into<String>>(expected: S, got: &Envelope) -> Error {
+    Error::ProtocolViolation {
+        expected: expected.into(),
+        got: format!("{:?}", got.packet),
+    }
+}
+
+/// The response to a request, or its error. If the request failed
+/// with `Error::Disconnected` the broker has no record of it, so its
+/// Pid is freed rather than orphaned.
+fn response_or_free<T>(res: Result<T>, pid: PidGuard) -> Result<(T, PidGuard)> {
+    match res {
+        Ok(v) => Ok((v, pid)),
+        Err(Error::Disconnected) => {
+            pid.free()?;
+            Err(Error::Disconnected)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// The reason codes from a Suback. With MQTT 3.1.1 these are derived
+/// from the return codes.
+fn suback_reason_codes(
+    return_codes: &[SubscribeReturnCodes],
+    reason_codes: Vec<ReasonCode>,
+) -> Vec<ReasonCode> {
+    if !reason_codes.is_empty() {
+        return reason_codes;
     }
+    return_codes.iter().map(|rc| match rc {
+        SubscribeReturnCodes::Success(QoS::AtMostOnce) => ReasonCode::Success,
+        SubscribeReturnCodes::Success(QoS::AtLeastOnce) => ReasonCode::GrantedQoS1,
+        SubscribeReturnCodes::Success(QoS::ExactlyOnce) => ReasonCode::GrantedQoS2,
+        SubscribeReturnCodes::Failure => ReasonCode::UnspecifiedError,
+    }).collect()
 }
 
 /// Build a connect packet from ClientOptions.
-fn connect_packet(opts: &ClientOptions) -> Result<Packet> {
-    Ok(Packet::Connect(mqttrs::Connect {
+fn connect_packet(opts: &ClientOptions) -> Result<Envelope> {
+    let mut e = Envelope::from(Packet::Connect(mqttrs::Connect {
         protocol: mqttrs::Protocol::MQTT311,
         keep_alive: match opts.keep_alive {
             KeepAlive::Disabled => 0,
@@ -581,11 +1254,40 @@ fn connect_packet(opts: &ClientOptions) -> Result<Packet> {
             None => "".to_owned(),
             Some(cid) => cid.to_owned(),
         },
-        clean_session: true, // TODO
-        last_will: None, // TODO
+        clean_session: opts.clean_session,
+        last_will: opts.last_will.as_ref().map(|lw| mqttrs::LastWill {
+            topic: lw.topic().to_owned(),
+            message: lw.payload().to_owned(),
+            qos: lw.qos(),
+            retain: lw.retain(),
+        }),
         username: opts.username.clone(),
         password: opts.password.clone(),
-    }))
+    }));
+    if opts.protocol_version == ProtocolVersion::V5 {
+        e.properties = opts.connect_properties.clone();
+        if let Some(ref lw) = opts.last_will {
+            e.will_properties = lw.properties().clone();
+        }
+        if !opts.clean_session && e.properties.session_expiry_interval.is_none() {
+            // Without this an MQTT 5 session ends when the connection
+            // closes, unlike MQTT 3.1.1.
+            e.properties.session_expiry_interval = Some(u32::MAX);
+        }
+    }
+    Ok(e)
+}
+
+/// Returns `Error::ReasonCode` if `e` has an MQTT 5 reason code that
+/// indicates failure.
+fn check_reason_codes(e: &Envelope) -> Result<()> {
+    match e.failure() {
+        Some(reason_code) => Err(Error::ReasonCode {
+            reason_code,
+            reason_string: e.properties.reason_string.clone(),
+        }),
+        None => Ok(()),
+    }
 }
 
 fn packet_pid(p: &Packet) -> Option<Pid> {
@@ -613,7 +1315,7 @@ enum SelectResult {
     IoReq(Option<IoRequest>),
 
     /// Read a packet from the network
-    Read(Result<Packet>),
+    Read(Result<Envelope>),
 
     /// Time to send a keep-alive ping request packet.
     Ping,
@@ -625,39 +1327,77 @@ enum SelectResult {
 impl IoTask {
     async fn run(mut self) {
         loop {
-            if self.halt.load(Ordering::SeqCst) {
-                self.shutdown_conn().await;
+            if self.halt.is_set() {
+                self.close_conn(DisconnectReason::Client).await;
                 debug!("IoTask: halting by request.");
                 self.state = IoTaskState::Halted;
+                self.fail_requests(|| Error::Disconnected).await;
                 return;
             }
 
             match self.state {
                 IoTaskState::Halted => return,
-                IoTaskState::Disconnected =>
+                IoTaskState::Disconnected => {
+                    self.event(ConnectionEvent::Connecting);
                     match Self::try_connect(&mut self).await {
                         Err(e) => {
                             error!("IoTask: Error connecting: {}", e);
-                            if self.options.automatic_connect {
-                                sleep(self.options.connect_retry_delay).await;
-                            } else {
+                            let e = Arc::new(e);
+                            self.event(ConnectionEvent::ConnectFailed { error: e.clone() });
+                            if !self.connect_failed() {
+                                // Try the next endpoint straight away.
+                                continue;
+                            }
+                            if !self.options.automatic_connect {
                                 info!("IoTask: halting due to connection failure, auto connect is off.");
                                 self.state = IoTaskState::Halted;
                                 return;
                             }
+                            self.reconnect_attempts += 1;
+                            match self.options.reconnect_policy.delay(self.reconnect_attempts,
+                                                                      self.reconnect_delay) {
+                                Some(delay) => {
+                                    debug!("IoTask: Retrying connect in {}ms",
+                                           delay.as_millis());
+                                    self.reconnect_delay = delay;
+                                    self.event(ConnectionEvent::Reconnecting {
+                                        attempt: self.reconnect_attempts,
+                                        delay,
+                                    });
+                                    self.halt.sleep(delay).await;
+                                },
+                                None => {
+                                    self.give_up(&e).await;
+                                    return;
+                                },
+                            }
                         },
                         Ok(()) => {
-                            if let Err(e) = Self::replay_subscriptions(&mut self).await {
+                            self.event(ConnectionEvent::Connected {
+                                session_present: self.session_present.load(Ordering::SeqCst),
+                            });
+                            if let Err(e) = Self::retransmit_in_flight(&mut self).await {
+                                error!("IoTask: Error retransmitting requests on reconnect: {}",
+                                       e);
+                            }
+                            if self.session_present.load(Ordering::SeqCst) {
+                                debug!("IoTask: Session resumed, not replaying subscriptions");
+                            } else if let Err(e) = Self::replay_subscriptions(&mut self).await {
                                 error!("IoTask: Error replaying subscriptions on reconnect: {}",
                                        e);
                             }
                         },
-                    },
+                    }
+                },
                 IoTaskState::Connected(_) =>
                     match Self::run_once_connected(&mut self).await {
                         Err(Error::Disconnected) => {
                             info!("IoTask: Disconnected, resetting state");
                             self.state = IoTaskState::Disconnected;
+                            self.connection_lost();
+                            if !self.halt.is_set() {
+                                self.wait_to_reconnect().await;
+                            }
                         },
                         Err(e) => {
                             error!("IoTask: Quitting run loop due to error: {}", e);
@@ -669,8 +1409,99 @@ impl IoTask {
         }
     }
 
+    /// Choose the next endpoint after a failed connection attempt.
+    ///
+    /// Returns true if every endpoint has failed since the last
+    /// success, in which case the caller should wait before retrying.
+    fn connect_failed(&mut self) -> bool {
+        let n = self.options.endpoints.len();
+        self.endpoint_index = (self.endpoint_index + 1) % n;
+        self.failed_connects += 1;
+        if self.failed_connects < n {
+            return false;
+        }
+        self.failed_connects = 0;
+        if self.options.endpoint_policy == EndpointPolicy::Primary {
+            self.endpoint_index = 0;
+        }
+        true
+    }
+
+    /// Stop connecting after the reconnect policy gives up, and fail
+    /// all outstanding and queued requests.
+    async fn give_up(&mut self, last_error: &Error) {
+        let gave_up = GaveUp {
+            attempts: self.reconnect_attempts,
+            last_error: last_error.to_string(),
+        };
+        error!("IoTask: Giving up connecting after {} attempts", gave_up.attempts);
+        *self.gave_up.lock().expect("gave_up lock") = Some(gave_up.clone());
+        self.state = IoTaskState::Halted;
+        self.fail_requests(|| gave_up.to_error()).await;
+    }
+
+    /// Stop accepting IO requests, and answer every queued and
+    /// in-flight request with an error from `err`, except that
+    /// requests to shut down succeed. Also ends all `Subscription`
+    /// streams.
+    async fn fail_requests<F: Fn() -> Error>(&mut self, err: F) {
+        self.router.lock().expect("router lock").close();
+        let in_flight = std::mem::take(&mut self.pid_response_map);
+        self.rx_io_requests.close();
+        let mut queued = vec![];
+        while let Some(req) = self.rx_io_requests.recv().await {
+            queued.push(req);
+        }
+        for req in in_flight.into_values().chain(queued) {
+            let result = match req.io_type {
+                IoType::ShutdownConnection => Ok(None),
+                _ => Err(err()),
+            };
+            // send_io_result only logs errors.
+            let _ = Self::send_io_result(req, IoResult { result });
+        }
+    }
+
+    /// Choose the next endpoint after losing an established connection.
+    fn connection_lost(&mut self) {
+        *self.connected_endpoint.lock().expect("connected_endpoint lock") = None;
+        self.endpoint_index = match self.options.endpoint_policy {
+            EndpointPolicy::RoundRobin =>
+                (self.endpoint_index + 1) % self.options.endpoints.len(),
+            EndpointPolicy::Primary => 0,
+        };
+    }
+
+    /// Wait a random moment before reconnecting after losing an
+    /// established connection, up to `RECONNECT_JITTER` or the
+    /// reconnect policy's first delay if that is shorter. This stops
+    /// clients that lost their connections at the same moment, e.g.
+    /// when a broker restarts, reconnecting in lockstep.
+    async fn wait_to_reconnect(&mut self) {
+        let max = match self.options.reconnect_policy.delay(1, Duration::from_secs(0)) {
+            Some(delay) => delay.min(RECONNECT_JITTER),
+            // No attempt has failed yet, so don't give up.
+            None => return,
+        };
+        let delay = Duration::from_millis(
+            rand::thread_rng().gen_range(0..=max.as_millis() as u64));
+        debug!("IoTask: Reconnecting in {}ms", delay.as_millis());
+        self.event(ConnectionEvent::Reconnecting { attempt: 0, delay });
+        self.halt.sleep(delay).await;
+    }
+
     async fn try_connect(&mut self) -> Result<()> {
-        let stream = connect_stream(&self.options).await?;
+        let ep = self.options.endpoints[self.endpoint_index].clone();
+        // Covers the proxy tunnel and the TLS and WebSocket handshakes,
+        // which could otherwise hang forever.
+        let stream = match timeout(self.options.operation_timeout,
+                                   connect_stream(&self.options, &ep)).await {
+            Err(Elapsed { .. }) => return Err(Error::Timeout {
+                operation: "connecting",
+                after: self.options.operation_timeout,
+            }),
+            Ok(res) => res?,
+        };
         self.state =  IoTaskState::Connected(IoTaskConnected {
             stream: stream,
             read_buf: BytesMut::with_capacity(self.options.max_packet_len),
@@ -678,7 +1509,7 @@ impl IoTask {
             last_write_time: Instant::now(),
             last_pingreq_time: Instant::now(),
             last_pingresp_time: Instant::now(),
-            pid_response_map: BTreeMap::new(),
+            topic_aliases: BTreeMap::new(),
         });
         let c = match self.state {
             IoTaskState::Connected(ref mut c) => c,
@@ -690,33 +1521,63 @@ impl IoTask {
         let read = Self::read_packet(&mut c.stream,
                                      &mut c.read_buf,
                                      &mut c.read_bufn,
-                                     self.options.max_packet_len);
+                                     self.options.max_packet_len,
+                                     self.options.protocol_version);
         let res = match timeout(self.options.operation_timeout,
                                 read).await {
             // Timeout
             Err(Elapsed { .. }) =>
-                Err(format!("Timeout waiting for Connack after {}ms",
-                            self.options.operation_timeout.as_millis()).into()),
+                Err(Error::Timeout {
+                    operation: "waiting for Connack",
+                    after: self.options.operation_timeout,
+                }),
 
             // Non-timeout error
             Ok(Err(e)) => Err(e),
 
-            Ok(Ok(Packet::Connack(ca))) => {
+            Ok(Ok(Envelope { packet: Packet::Connack(ca), ref reason_codes, ref properties, .. }))
+                if !reason_codes.is_empty() && !reason_codes[0].is_success() => {
+                debug!("IoTask: connack with code={:?}, session_present={}.",
+                       reason_codes[0], ca.session_present);
+                Err(Error::ReasonCode {
+                    reason_code: reason_codes[0],
+                    reason_string: properties.reason_string.clone(),
+                })
+            },
+
+            Ok(Ok(Envelope { packet: Packet::Connack(ca), .. })) => {
                 match ca.code {
                     ConnectReturnCode::Accepted => {
-                        debug!("IoTask: connack with code=Accepted.");
+                        debug!("IoTask: connack with code=Accepted, session_present={}.",
+                               ca.session_present);
+                        self.session_present.store(ca.session_present, Ordering::SeqCst);
+                        if !ca.session_present {
+                            // The broker has no session state, so it
+                            // won't send Pubrel for anything received before,
+                            // or respond to anything sent before.
+                            self.qos2_received.clear();
+                            self.orphaned_pubrels.clear();
+                            self.write_pids.free_orphaned();
+                        }
                         Ok(())
                     },
-                    _ => Err(format!("Bad connect return code: {:?}", ca.code).into()),
+                    _ => Err(Error::ConnectionRefused(ca.code)),
                 }
             },
 
             // Other unexpected packets.
-            Ok(Ok(p)) =>
-                Err(format!("Received packet not CONNACK after connect: {:?}", p).into()),
+            Ok(Ok(p)) => Err(unexpected_packet("Connack", &p)),
         };
         match res {
-            Ok(()) => Ok(()),
+            Ok(()) => {
+                info!("IoTask: Connected to {}:{}", ep.host(), ep.port());
+                self.connection_id += 1;
+                self.failed_connects = 0;
+                self.reconnect_attempts = 0;
+                self.reconnect_delay = Duration::from_secs(0);
+                *self.connected_endpoint.lock().expect("connected_endpoint lock") = Some(ep);
+                Ok(())
+            },
             Err(e) => {
                 self.shutdown_conn().await;
                 Err(e)
@@ -724,6 +1585,45 @@ impl IoTask {
         }
     }
 
+    /// Send a connection event to any `Client::events` streams.
+    fn event(&self, event: ConnectionEvent) {
+        trace!("IoTask: Connection event {:?}", event);
+        // This only fails when there are no streams.
+        let _ = self.events_tx.send(event);
+    }
+
+    /// Shutdown the network connection to the MQTT broker, and send a
+    /// `ConnectionEvent::Disconnected` if it was connected.
+    async fn close_conn(&mut self, reason: DisconnectReason) {
+        if let IoTaskState::Connected(_) = self.state {
+            self.event(ConnectionEvent::Disconnected { reason });
+        }
+        self.shutdown_conn().await;
+    }
+
+    /// Close the connection because the broker broke the protocol,
+    /// first telling an MQTT 5 broker why with a Disconnect packet.
+    ///
+    /// Returns `Err(Error::Disconnected)` so the client reconnects.
+    async fn close_on_violation(&mut self, reason_code: ReasonCode, e: Error) -> Result<()> {
+        error!("IoTask: Closing the connection after a protocol violation: {}", e);
+        if let IoTaskState::Connected(ref mut c) = self.state {
+            if self.options.protocol_version == ProtocolVersion::V5 {
+                let p = Envelope {
+                    packet: Packet::Disconnect,
+                    reason_codes: vec![reason_code],
+                    properties: Properties::default(),
+                    will_properties: Properties::default(),
+                };
+                if let Err(e) = Self::write_packet(&self.options, c, &p).await {
+                    error!("IoTask: Failed to write Disconnect: {:?}", e);
+                }
+            }
+        }
+        self.close_conn(DisconnectReason::ProtocolError).await;
+        Err(Error::Disconnected)
+    }
+
     /// Shutdown the network connection to the MQTT broker.
     ///
     /// Logs and swallows errors.
@@ -745,25 +1645,98 @@ impl IoTask {
         self.state = IoTaskState::Disconnected;
     }
 
-    async fn replay_subscriptions(&mut self) -> Result<()> {
-        // NB: Some duplication in Client::subscribe and Client::write_request.
-        let subs = self.subscriptions.clone();
-        for (t, qos) in subs.iter() {
-            trace!("Replaying subscription topic='{}' qos={:?}", t, qos);
-            // Pick a high pid to probably avoid collisions with one allocated
-            // by the Client.
-            let pid = Pid::try_from(65535).expect("non-zero pid");
-            let p = Packet::Subscribe(mqttrs::Subscribe {
-                pid,
-                topics: vec![SubscribeTopic { topic_path: t.to_owned(), qos: qos.to_owned() }]
-            });
-            let req = IoRequest {
-                io_type: IoType::WriteAndResponse { packet: p, response_pid: pid },
-                // TODO: I'm not sure how to receive the result; ignore it for now.
-                tx_result: None,
-            };
+    /// Resend the requests that were in flight when the previous
+    /// connection was lost.
+    ///
+    /// Publishes are resent with the DUP flag set if the broker resumed
+    /// the session, or as new publishes if it has no record of them.
+    /// Pubrels are resent unchanged, including those for orphaned Pids.
+    /// Requests the Client has stopped waiting for are resent too if
+    /// the broker resumed the session, so their late responses free
+    /// their Pids. Subscribes and unsubscribes fail with
+    /// `Error::Disconnected`; `replay_subscriptions` restores the
+    /// subscriptions themselves.
+    async fn retransmit_in_flight(&mut self) -> Result<()> {
+        let in_flight = std::mem::take(&mut self.pid_response_map);
+        let session_present = self.session_present.load(Ordering::SeqCst);
+        for (pid, mut req) in in_flight.into_iter() {
+            if req.is_abandoned() && !session_present {
+                // free_orphaned has already freed its Pid.
+                debug!("IoTask: Dropping abandoned request Pid={:?}", pid);
+                continue;
+            }
+            if !req.io_type.retransmit() {
+                if req.is_abandoned() {
+                    // No response will arrive, so free its orphaned Pid.
+                    if pid.get() != REPLAY_PID {
+                        self.write_pids.late_response(pid);
+                    }
+                    continue;
+                }
+                let res = IoResult { result: Err(Error::Disconnected) };
+                Self::send_io_result(req, res)?;
+                continue;
+            }
+            if let IoType::WriteAndResponse {
+                packet: Envelope { packet: Packet::Publish(ref mut p), .. }, ..
+            } = req.io_type
+            {
+                p.dup = session_present;
+            }
+            trace!("IoTask: Retransmitting Pid={:?}", pid);
             self.handle_io_req(req).await?;
         }
+        for pid in self.orphaned_pubrels.clone() {
+            trace!("IoTask: Retransmitting orphaned Pubrel Pid={:?}", pid);
+            self.write_orphaned_pubrel(pid).await;
+        }
+        Ok(())
+    }
+
+    /// Resubscribe to all active subscriptions in one Subscribe
+    /// packet. A `ConnectionEvent::SubscriptionsReplayed` is sent when
+    /// the Suback arrives.
+    async fn replay_subscriptions(&mut self) -> Result<()> {
+        // NB: Some duplication in ClientHandle::subscribe and ClientHandle::write_request.
+        if self.subscriptions.is_empty() {
+            return Ok(());
+        }
+        let topics = self.subscriptions.iter()
+            .map(|(t, qos)| SubscribeTopic { topic_path: t.to_owned(), qos: qos.to_owned() })
+            .collect::<Vec<_>>();
+        trace!("Replaying subscriptions {:?}", topics);
+        let filters = topics.iter().map(|t| t.topic_path.clone()).collect::<Vec<_>>();
+        // The Client never allocates this Pid.
+        let pid = Pid::try_from(REPLAY_PID).expect("non-zero pid");
+        let p = Packet::Subscribe(mqttrs::Subscribe { pid, topics });
+        let (tx, rx) = oneshot::channel::<IoResult>();
+        let req = IoRequest {
+            io_type: IoType::WriteAndResponse { packet: p.into(), response_pid: pid },
+            tx_result: Some(tx),
+        };
+        self.handle_io_req(req).await?;
+
+        // Wait for the Suback in another task so this one can read it.
+        let events_tx = self.events_tx.clone();
+        let operation_timeout = self.options.operation_timeout;
+        self.options.runtime.spawn(async move {
+            let res = match timeout(operation_timeout, rx).await {
+                Ok(Ok(IoResult { result: Ok(Some(res)) })) => res,
+                res => {
+                    error!("IoTask: No Suback for replayed subscriptions: {:?}", res);
+                    return;
+                },
+            };
+            match res.packet {
+                Packet::Suback(s) => {
+                    let results = filters.into_iter()
+                        .zip(suback_reason_codes(&s.return_codes, res.reason_codes))
+                        .collect();
+                    let _ = events_tx.send(ConnectionEvent::SubscriptionsReplayed { results });
+                },
+                p => error!("IoTask: Unexpected packet for replayed subscriptions: {:?}", p),
+            }
+        });
         Ok(())
     }
 
@@ -809,7 +1782,8 @@ impl IoTask {
             let mut req_fut = Box::pin(self.rx_io_requests.recv().fuse());
             let mut read_fut = Box::pin(
                 Self::read_packet(&mut c.stream, &mut c.read_buf, &mut c.read_bufn,
-                                  self.options.max_packet_len).fuse());
+                                  self.options.max_packet_len,
+                                  self.options.protocol_version).fuse());
             let mut ping_fut = match pingreq_next {
                 Some(t) => Box::pin(sleep_until(t).boxed().fuse()),
                 None => Box::pin(pending().boxed().fuse()),
@@ -831,7 +1805,7 @@ impl IoTask {
                 None => {
                     // Sender closed.
                     debug!("IoTask: Req stream closed, shutting down.");
-                    self.shutdown_conn().await;
+                    self.close_conn(DisconnectReason::Client).await;
                     return Err(Error::Disconnected);
                 },
                 Some(req) => return self.handle_io_req(req).await,
@@ -841,13 +1815,14 @@ impl IoTask {
                 // We timed out waiting for a ping response from
                 // the server, shutdown the stream.
                 debug!("IoTask: Timed out waiting for Pingresp, shutting down.");
-                self.shutdown_conn().await;
+                self.event(ConnectionEvent::PingTimeout);
+                self.close_conn(DisconnectReason::PingTimeout).await;
                 return Err(Error::Disconnected);
             }
         }
     }
 
-    async fn handle_read(&mut self, read: Result<Packet>) -> Result<()> {
+    async fn handle_read(&mut self, read: Result<Envelope>) -> Result<()> {
         let c = match self.state {
             IoTaskState::Connected(ref mut c) => c,
             _ => panic!("Not reached"),
@@ -855,32 +1830,158 @@ impl IoTask {
 
         match read {
             Err(Error::Disconnected) => {
+                self.close_conn(DisconnectReason::ConnectionLost).await;
                 return Err(Error::Disconnected);
             }
+            Err(e @ Error::ProtocolViolation { .. }) => {
+                // The rest of the stream can't be trusted, and reading
+                // on would decode the same bytes again.
+                return self.close_on_violation(ReasonCode::MalformedPacket, e).await;
+            },
             Err(e) => {
                 error!("IoTask: Failed to read packet: {:?}", e);
+                self.close_conn(DisconnectReason::ConnectionLost).await;
+                return Err(Error::Disconnected);
             },
-            Ok(p) => {
-                match p {
+            Ok(mut p) => {
+                if let Packet::Publish(ref mut publish) = p.packet {
+                    if let Err(e) = Self::resolve_topic_alias(c, publish, &p.properties) {
+                        return self.close_on_violation(ReasonCode::TopicAliasInvalid, e).await;
+                    }
+                }
+                match p.packet {
                     Packet::Pingresp => {
                         debug!("IoTask: Received Pingresp");
                         c.last_pingresp_time = Instant::now();
                     },
-                    Packet::Publish(_) => {
-                        if let Err(e) = self.tx_recv_published.send(p).await {
-                            error!("IoTask: Failed to send Packet: {:?}", e);
+                    Packet::Publish(mqttrs::Publish {
+                        qospid: QosPid::ExactlyOnce(pid), ..
+                    }) if self.qos2_received.contains(&pid) => {
+                        // Redelivery of a publish the Client already has,
+                        // so just acknowledge it again.
+                        debug!("IoTask: Received duplicate QoS::ExactlyOnce publish Pid={:?}",
+                               pid);
+                        c.last_write_time = Instant::now();
+                        let p = Packet::Pubrec(pid).into();
+                        if let Err(e) = Self::write_packet(&self.options, c, &p).await {
+                            error!("IoTask: Failed to write Pubrec: {:?}", e);
+                        }
+                    },
+                    Packet::Publish(ref publish) => {
+                        if let QosPid::ExactlyOnce(pid) = publish.qospid {
+                            self.qos2_received.insert(pid);
+                        }
+                        let received_at = SystemTime::now();
+                        let routes = self.router.lock().expect("router lock")
+                                         .matches(&publish.topic_name);
+                        if routes.is_empty() {
+                            let filter = self.subscriptions.keys()
+                                .find(|f| topic_matches(f, &publish.topic_name))
+                                .cloned();
+                            let rr = ReadResult::new(publish, &p.properties, filter, received_at,
+                                                     self.connection_id);
+                            if let Err(e) = self.tx_recv_published.send(Ok(rr)).await {
+                                error!("IoTask: Failed to send Packet: {:?}", e);
+                            }
+                            return Ok(());
+                        }
+                        let unacked_copies = Arc::new(AtomicUsize::new(routes.len()));
+                        let mut undelivered = 0;
+                        let mut overflowed = 0;
+                        for (filter, tx) in routes {
+                            let mut rr = ReadResult::new(publish, &p.properties, Some(filter),
+                                                         received_at, self.connection_id);
+                            rr.unacked_copies = unacked_copies.clone();
+                            // Don't wait for a slow Subscription, which
+                            // would stop this task answering the broker.
+                            match tx.try_send(rr) {
+                                Ok(()) => (),
+                                Err(TrySendError::Closed(_)) => {
+                                    debug!("IoTask: Subscription dropped before delivery");
+                                    undelivered += 1;
+                                },
+                                Err(TrySendError::Full(rr)) => {
+                                    error!("IoTask: Subscription for '{}' is full, dropping \
+                                            publish to '{}'",
+                                           rr.subscription_filter().unwrap_or_default(),
+                                           publish.topic_name);
+                                    overflowed += 1;
+                                },
+                            }
+                        }
+                        if overflowed > 0 && publish.qospid != QosPid::AtMostOnce {
+                            // Don't acknowledge it, so the broker sends it
+                            // again later. With manual acks the overflowed
+                            // copies are never acknowledged either.
+                            if let QosPid::ExactlyOnce(pid) = publish.qospid {
+                                self.qos2_received.remove(&pid);
+                            }
+                            return Ok(());
+                        }
+                        // With manual acks each Subscription stream gives its
+                        // copy an Ack token, and the last copy acknowledged
+                        // sends the acknowledgement. Otherwise Subscription
+                        // streams can't acknowledge messages, so do it here.
+                        if self.options.manual_acks &&
+                            (undelivered == 0 ||
+                             unacked_copies.fetch_sub(undelivered, Ordering::AcqRel)
+                                 > undelivered) {
+                            return Ok(());
+                        }
+                        let ack = match publish.qospid {
+                            QosPid::AtMostOnce => return Ok(()),
+                            QosPid::AtLeastOnce(pid) => Packet::Puback(pid),
+                            QosPid::ExactlyOnce(pid) => Packet::Pubrec(pid),
+                        };
+                        c.last_write_time = Instant::now();
+                        if let Err(e) = Self::write_packet(&self.options, c, &ack.into()).await {
+                            error!("IoTask: Failed to write acknowledgement: {:?}", e);
+                        }
+                    },
+                    Packet::Pubrel(pid) => {
+                        trace!("IoTask: Received Pubrel Pid={:?}", pid);
+                        self.qos2_received.remove(&pid);
+                        c.last_write_time = Instant::now();
+                        let p = Packet::Pubcomp(pid).into();
+                        if let Err(e) = Self::write_packet(&self.options, c, &p).await {
+                            error!("IoTask: Failed to write Pubcomp: {:?}", e);
                         }
                     },
-                    Packet::Connack(_) => {
-                        error!("IoTask: Unexpected CONNACK in handle_read(): {:?}", p);
-                        self.shutdown_conn().await;
+                    Packet::Connack(_) |
+                    Packet::Connect(_) |
+                    Packet::Subscribe(_) |
+                    Packet::Unsubscribe(_) |
+                    Packet::Pingreq => {
+                        let e = unexpected_packet("a packet a server may send", &p);
+                        return self.close_on_violation(ReasonCode::ProtocolError, e).await;
+                    }
+                    Packet::Disconnect => {
+                        info!("IoTask: Server sent Disconnect: reason_codes={:?}",
+                              p.reason_codes);
+                        let reason_code = p.reason_codes.first().copied()
+                                           .unwrap_or(ReasonCode::Success);
+                        let reason_string = p.properties.reason_string;
+                        let e = Error::ServerDisconnected {
+                            reason_code,
+                            reason_string: reason_string.clone(),
+                        };
+                        let reason = DisconnectReason::Server { reason_code, reason_string };
+                        // Tell the Client why, if it has room to hear it.
+                        if let Err(e) = self.tx_recv_published.try_send(Err(e)) {
+                            error!("IoTask: Failed to send Disconnect: {:?}", e);
+                        }
+                        self.close_conn(reason).await;
                         return Err(Error::Disconnected);
                     }
                     _ => {
-                        let pid = packet_pid(&p);
+                        let pid = packet_pid(&p.packet);
                         if let Some(pid) = pid {
-                            let pid_response = c.pid_response_map.remove(&pid);
+                            let pid_response = self.pid_response_map.remove(&pid);
                             match pid_response {
+                                Some(req) if req.is_abandoned() =>
+                                    self.late_response(pid, &p).await,
+                                None if self.orphaned_pubrels.contains(&pid) =>
+                                    self.late_response(pid, &p).await,
                                 None => error!("Unknown PID: {:?}", pid),
                                 Some(req) => {
                                     trace!("Sending response PID={:?} p={:?}",
@@ -897,7 +1998,74 @@ impl IoTask {
         Ok(())
     }
 
+    /// Handle a response to a request the Client stopped waiting for,
+    /// whose Pid is orphaned.
+    ///
+    /// A late Pubrec is answered with a Pubrel so the broker releases
+    /// the Pid. Otherwise the response completes the request, so the
+    /// Pid is freed.
+    async fn late_response(&mut self, pid: Pid, p: &Envelope) {
+        debug!("IoTask: Late response for Pid={:?}: {:?}", pid, p.packet);
+        if pid.get() == REPLAY_PID {
+            return;
+        }
+        if let Packet::Pubrec(_) = p.packet {
+            if p.failure().is_none() {
+                self.write_orphaned_pubrel(pid).await;
+                return;
+            }
+        }
+        self.orphaned_pubrels.remove(&pid);
+        self.write_pids.late_response(pid);
+    }
+
+    /// Write a Pubrel for an orphaned QoS::ExactlyOnce publish.
+    async fn write_orphaned_pubrel(&mut self, pid: Pid) {
+        self.orphaned_pubrels.insert(pid);
+        let c = match self.state {
+            IoTaskState::Connected(ref mut c) => c,
+            _ => return,
+        };
+        c.last_write_time = Instant::now();
+        let p = Packet::Pubrel(pid).into();
+        if let Err(e) = Self::write_packet(&self.options, c, &p).await {
+            error!("IoTask: Failed to write Pubrel: {:?}", e);
+        }
+    }
+
+    /// Resolve an MQTT 5 topic alias on a received publish, or record
+    /// a new one.
+    fn resolve_topic_alias(
+        c: &mut IoTaskConnected,
+        p: &mut mqttrs::Publish,
+        properties: &Properties,
+    ) -> Result<()> {
+        let alias = match properties.topic_alias {
+            Some(alias) => alias,
+            None => return Ok(()),
+        };
+        if p.topic_name.is_empty() {
+            match c.topic_aliases.get(&alias) {
+                Some(topic) => p.topic_name = topic.clone(),
+                None => return Err(Error::ProtocolViolation {
+                    expected: "a known topic alias".to_owned(),
+                    got: format!("topic alias {}", alias),
+                }),
+            }
+        } else {
+            c.topic_aliases.insert(alias, p.topic_name.clone());
+        }
+        Ok(())
+    }
+
     async fn handle_io_req(&mut self, req: IoRequest) -> Result<()> {
+        if let IoType::Acknowledge { connection_id, .. } = req.io_type {
+            if connection_id != self.connection_id {
+                // The Pid may belong to another message by now.
+                debug!("IoTask: Not acknowledging a message from an earlier connection");
+                return Self::send_io_result(req, IoResult { result: Ok(None) });
+            }
+        }
         let c = match self.state {
             IoTaskState::Connected(ref mut c) => c,
             _ => panic!("Not reached"),
@@ -908,11 +2076,18 @@ impl IoTask {
             let res = Self::write_packet(&self.options, c, &p).await;
             if let Err(e) = res {
                 error!("IoTask: Error writing packet: {:?}", e);
+                if let IoType::WriteAndResponse { response_pid, .. } = req.io_type {
+                    if req.io_type.retransmit() {
+                        // Keep the request to retransmit after reconnecting.
+                        self.pid_response_map.insert(response_pid, req);
+                        return Ok(())
+                    }
+                }
                 let res = IoResult { result: Err(e) };
                 Self::send_io_result(req, res)?;
                 return Ok(())
             }
-            match p {
+            match &p.packet {
                 Packet::Subscribe(s) => {
                     for st in s.topics.iter() {
                         trace!("Tracking subscription topic='{}', qos={:?}",
@@ -929,12 +2104,12 @@ impl IoTask {
                 _ => {},
             }
             match req.io_type {
-                IoType::WriteOnly { .. } => {
+                IoType::WriteOnly { .. } | IoType::Acknowledge { .. } => {
                     let res = IoResult { result: res.map(|_| None) };
                     Self::send_io_result(req, res)?;
                 },
                 IoType::WriteAndResponse { response_pid, .. } => {
-                    c.pid_response_map.insert(response_pid, req);
+                    self.pid_response_map.insert(response_pid, req);
                 },
                 IoType::ShutdownConnection => {
                     panic!("Not reached because ShutdownConnection has no packet")
@@ -944,7 +2119,7 @@ impl IoTask {
             match req.io_type {
                 IoType::ShutdownConnection => {
                     debug!("IoTask: IoType::ShutdownConnection.");
-                    self.shutdown_conn().await;
+                    self.close_conn(DisconnectReason::Client).await;
                     let res = IoResult { result: Ok(None) };
                     Self::send_io_result(req, res)?;
                     return Err(Error::Disconnected);
@@ -977,7 +2152,7 @@ impl IoTask {
         debug!("IoTask: Writing Pingreq");
         c.last_write_time = Instant::now();
         c.last_pingreq_time = Instant::now();
-        let p = Packet::Pingreq;
+        let p = Packet::Pingreq.into();
         if let Err(e) = Self::write_packet(&self.options, c, &p).await {
             error!("IoTask: Failed to write ping: {:?}", e);
         }
@@ -987,18 +2162,20 @@ impl IoTask {
     async fn write_packet(
         opts: &ClientOptions,
         c: &mut IoTaskConnected,
-        p: &Packet,
+        p: &Envelope,
     ) -> Result<()> {
         if cfg!(feature = "unsafe-logging") {
             trace!("write_packet p={:#?}", p);
         }
         // TODO: Test long packets.
         let mut bytes = BytesMut::with_capacity(opts.max_packet_len);
-        mqttrs::encode(&p, &mut bytes)?;
+        codec::encode(p, opts.protocol_version, &mut bytes)?;
         if cfg!(feature = "unsafe-logging") {
             trace!("write_packet bytes p={:?}", &*bytes);
         }
         c.stream.write_all(&*bytes).await?;
+        // Some streams buffer writes, e.g. WebSocket.
+        c.stream.flush().await?;
         Ok(())
     }
 
@@ -1006,8 +2183,9 @@ impl IoTask {
         stream: &mut AsyncStream,
         read_buf: &mut BytesMut,
         read_bufn: &mut usize,
-        max_packet_len: usize
-    ) -> Result<Packet> {
+        max_packet_len: usize,
+        protocol_version: ProtocolVersion,
+    ) -> Result<Envelope> {
         // TODO: Test long packets.
         loop {
             if cfg!(feature = "unsafe-logging") {
@@ -1017,7 +2195,12 @@ impl IoTask {
                 // We already have some bytes in the buffer. Try to decode a packet
                 read_buf.split_off(*read_bufn);
                 let old_len = read_buf.len();
-                let decoded = mqttrs::decode(read_buf)?;
+                let decoded = codec::decode(read_buf, protocol_version);
+                if decoded.is_err() {
+                    // The malformed packet has been consumed from the buffer.
+                    *read_bufn -= old_len - read_buf.len();
+                }
+                let decoded = decoded?;
                 if cfg!(feature = "unsafe-logging") {
                     trace!("read_packet decoded={:#?}", decoded);
                 }
@@ -1046,23 +2229,427 @@ impl IoTask {
     }
 }
 
+impl IoRequest {
+    /// Returns true if the Client is no longer waiting for the result.
+    fn is_abandoned(&self) -> bool {
+        match self.tx_result {
+            Some(ref tx) => tx.is_closed(),
+            None => false,
+        }
+    }
+}
+
 impl IoType {
-    fn packet(&self) -> Option<&Packet> {
+    fn packet(&self) -> Option<&Envelope> {
         match self {
             IoType::ShutdownConnection => None,
             IoType::WriteOnly { packet } => Some(&packet),
+            IoType::Acknowledge { packet, .. } => Some(packet),
             IoType::WriteAndResponse { packet, .. } => Some(&packet),
         }
     }
+
+    /// Returns true if this request should be resent after
+    /// reconnecting, i.e. it is part of a QoS::AtLeastOnce or
+    /// QoS::ExactlyOnce publish.
+    fn retransmit(&self) -> bool {
+        matches!(self,
+                 IoType::WriteAndResponse {
+                     packet: Envelope { packet: Packet::Publish(_), .. }, ..
+                 } |
+                 IoType::WriteAndResponse {
+                     packet: Envelope { packet: Packet::Pubrel(_), .. }, ..
+                 })
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Client;
+    use super::{
+        Client,
+        ClientHandle,
+        RECONNECT_JITTER,
+    };
+    use crate::{
+        client::{
+            ConnectFuture,
+            ConnectionEvent,
+            Connector,
+            DisconnectReason,
+            Endpoint,
+            FixedDelay,
+            LastWill,
+            MemoryStore,
+            Publish,
+            Subscribe,
+            fake_broker::fake_broker,
+        },
+        Error,
+    };
+    use futures_util::StreamExt;
+    use mqttrs::{Packet, QoS, QosPid, Suback, SubscribeReturnCodes, SubscribeTopic};
+    use tokio::time::{Duration, Instant, timeout};
 
     #[test]
     fn client_is_send() {
         let c = Client::builder().set_host("localhost".to_owned()).build().unwrap();
         let _s: &dyn Send = &c;
     }
+
+    #[test]
+    fn client_handle_is_send_sync() {
+        fn check<T: Clone + Send + Sync>() {}
+        check::<ClientHandle>();
+    }
+
+    #[test]
+    fn last_will_validated() {
+        let build = |topic: &str| {
+            Client::builder()
+                .set_host("localhost".to_owned())
+                .set_last_will(Some(LastWill::new(topic.to_owned(), b"offline".to_vec())))
+                .build()
+        };
+        assert!(build("devices/1/status").is_ok());
+        assert!(build("").is_err());
+        assert!(build("devices/+/status").is_err());
+        assert!(build("devices/#").is_err());
+    }
+
+    #[test]
+    fn endpoints_validated() {
+        let ep = || Endpoint::new("localhost".to_owned(), 1883);
+        assert!(Client::builder().set_endpoints(vec![ep(), ep()]).build().is_ok());
+        assert!(Client::builder().set_endpoints(vec![]).build().is_err());
+        assert!(Client::builder().build().is_err());
+        assert!(Client::builder()
+                .set_host("localhost".to_owned())
+                .set_endpoints(vec![ep()])
+                .build().is_err());
+    }
+
+    #[test]
+    fn invalid_state_errors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut c = Client::builder().set_host("localhost".to_owned()).build().unwrap();
+            let p = Publish::new("a".to_owned(), vec![]);
+            assert!(matches!(c.publish(&p).await, Err(Error::InvalidState(_))));
+            assert!(matches!(c.disconnect().await, Err(Error::InvalidState(_))));
+            assert!(matches!(c.handle(), Err(Error::InvalidState(_))));
+        })
+    }
+
+    #[test]
+    fn retransmit_after_reconnect() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            for &(qos, session_present) in [(QoS::AtLeastOnce, true),
+                                            (QoS::ExactlyOnce, false)].iter() {
+                let (connector, mut broker) = fake_broker();
+                let mut c = Client::builder()
+                    .set_host("fake".to_owned())
+                    .set_connector(connector)
+                    .set_connect_retry_delay(Duration::from_millis(10))
+                    .set_clean_session(false)
+                    .set_client_id(Some("c".to_owned()))
+                    .build().unwrap();
+                c.connect().await.unwrap();
+                let mut p = Publish::new("a".to_owned(), b"x".to_vec());
+                p.set_qos(qos);
+                let h = c.handle().unwrap();
+                let publish = tokio::spawn(async move { h.publish(&p).await });
+
+                // Drop the connection before acknowledging the publish.
+                let mut conn = broker.accept(false).await;
+                let first = match conn.read().await {
+                    Packet::Publish(p) => p,
+                    p => panic!("Unexpected packet {:?}", p),
+                };
+                assert!(!first.dup);
+                drop(conn);
+
+                let mut conn = broker.accept(session_present).await;
+                let again = match conn.read().await {
+                    Packet::Publish(p) => p,
+                    p => panic!("Unexpected packet {:?}", p),
+                };
+                // DUP only makes sense to a broker that kept the session.
+                assert_eq!(again.dup, session_present);
+                assert_eq!(again.qospid, first.qospid);
+                match again.qospid {
+                    QosPid::AtLeastOnce(pid) => conn.write(&Packet::Puback(pid)).await,
+                    QosPid::ExactlyOnce(pid) => {
+                        conn.write(&Packet::Pubrec(pid)).await;
+                        assert_eq!(conn.read().await, Packet::Pubrel(pid));
+                        conn.write(&Packet::Pubcomp(pid)).await;
+                    },
+                    QosPid::AtMostOnce => panic!("Unexpected QoS"),
+                }
+                publish.await.unwrap().unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn delay_before_reconnecting() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_reconnect_policy(FixedDelay::new(Duration::from_secs(10)))
+                .build().unwrap();
+            let mut events = c.events();
+            c.connect().await.unwrap();
+            drop(broker.accept(false).await);
+            let lost_at = Instant::now();
+            let _conn = broker.accept(false).await;
+            assert!(lost_at.elapsed() <= RECONNECT_JITTER + Duration::from_millis(500),
+                    "Reconnected after {:?}", lost_at.elapsed());
+
+            let reconnecting = timeout(Duration::from_secs(1), async {
+                loop {
+                    if let Some(ConnectionEvent::Reconnecting { attempt, delay }) =
+                        events.next().await
+                    {
+                        return (attempt, delay);
+                    }
+                }
+            }).await.unwrap();
+            assert_eq!(reconnecting.0, 0);
+            assert!(reconnecting.1 <= RECONNECT_JITTER, "Waited {:?}", reconnecting.1);
+        })
+    }
+
+    #[test]
+    fn disconnect_interrupts_reconnect_delay() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_reconnect_policy(FixedDelay::new(Duration::from_secs(10)))
+                .build().unwrap();
+            let mut events = c.events();
+            c.connect().await.unwrap();
+            drop(broker.accept(false).await);
+            drop(broker);
+            timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some(ConnectionEvent::Reconnecting { attempt: 1, .. }) =
+                        events.next().await
+                    {
+                        return;
+                    }
+                }
+            }).await.unwrap();
+            timeout(Duration::from_secs(1), c.disconnect()).await
+                .expect("disconnect waited out the reconnect delay")
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn full_subscription_does_not_block() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_packet_buffer_len(1)
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let h = c.handle().unwrap();
+            let sub = tokio::spawn(async move {
+                h.subscribe_stream(Subscribe::new(vec![
+                    SubscribeTopic { topic_path: "a".to_owned(), qos: QoS::AtLeastOnce },
+                ])).await
+            });
+            let mut conn = broker.accept(false).await;
+            let pid = match conn.read().await {
+                Packet::Subscribe(s) => s.pid,
+                p => panic!("Unexpected packet {:?}", p),
+            };
+            conn.write(&Packet::Suback(Suback {
+                pid,
+                return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)],
+            })).await;
+            let mut sub = sub.await.unwrap().unwrap();
+
+            // The second publish doesn't fit in the unread stream.
+            for n in 1..=2 {
+                let pid = mqttrs::Pid::try_from(n).unwrap();
+                conn.write(&Packet::Publish(mqttrs::Publish {
+                    dup: false,
+                    qospid: QosPid::AtLeastOnce(pid),
+                    retain: false,
+                    topic_name: "a".to_owned(),
+                    payload: vec![n as u8],
+                })).await;
+            }
+            // The client keeps answering, and only acknowledges the
+            // first publish.
+            let pid = mqttrs::Pid::try_from(3).unwrap();
+            conn.write(&Packet::Pubrel(pid)).await;
+            assert_eq!(conn.read().await, Packet::Puback(mqttrs::Pid::try_from(1).unwrap()));
+            assert_eq!(conn.read().await, Packet::Pubcomp(pid));
+            assert_eq!(sub.next().await.unwrap().payload(), &[1]);
+        })
+    }
+
+    #[test]
+    fn ack_after_reconnect_does_nothing() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_connect_retry_delay(Duration::from_millis(10))
+                .set_manual_acks(true)
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let pid = |n| mqttrs::Pid::try_from(n).unwrap();
+            let publish = Packet::Publish(mqttrs::Publish {
+                dup: false,
+                qospid: QosPid::AtLeastOnce(pid(1)),
+                retain: false,
+                topic_name: "a".to_owned(),
+                payload: vec![],
+            });
+            let mut conn = broker.accept(false).await;
+            conn.write(&publish).await;
+            let mut old = c.read_subscriptions().await.unwrap();
+            drop(conn);
+
+            // Pid 1 belongs to another message on the new connection.
+            let mut conn = broker.accept(false).await;
+            conn.write(&publish).await;
+            let mut new = c.read_subscriptions().await.unwrap();
+            old.ack().await.unwrap();
+            conn.write(&Packet::Pubrel(pid(2))).await;
+            assert_eq!(conn.read().await, Packet::Pubcomp(pid(2)));
+            new.ack().await.unwrap();
+            assert_eq!(conn.read().await, Packet::Puback(pid(1)));
+        })
+    }
+
+    #[test]
+    fn retry_stored_publish_after_error() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_session_store(MemoryStore::new())
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let mut conn = broker.accept(false).await;
+            // Let the store's task see the connection events first.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut p = Publish::new("a".to_owned(), b"x".to_vec());
+            p.set_qos(QoS::ExactlyOnce);
+            c.publish(&p).await.unwrap();
+
+            // A Puback is the wrong response, so the publish fails.
+            let first = match conn.read().await {
+                Packet::Publish(mqttrs::Publish { qospid: QosPid::ExactlyOnce(pid), .. }) => pid,
+                p => panic!("Unexpected packet {:?}", p),
+            };
+            conn.write(&Packet::Puback(first)).await;
+
+            // It is sent again without waiting for another publish or
+            // a reconnect.
+            let again = match conn.read().await {
+                Packet::Publish(mqttrs::Publish { qospid: QosPid::ExactlyOnce(pid), .. }) => pid,
+                p => panic!("Unexpected packet {:?}", p),
+            };
+            conn.write(&Packet::Pubrec(again)).await;
+            assert_eq!(conn.read().await, Packet::Pubrel(again));
+            conn.write(&Packet::Pubcomp(again)).await;
+        })
+    }
+
+    /// A connector for a proxy that never answers.
+    #[derive(Debug)]
+    struct HangingConnector;
+
+    impl Connector for HangingConnector {
+        fn connect<'a>(&'a self, _endpoint: &'a Endpoint) -> ConnectFuture<'a> {
+            Box::pin(futures_util::future::pending())
+        }
+    }
+
+    #[test]
+    fn connect_timeout() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(HangingConnector)
+                .set_operation_timeout(Duration::from_millis(50))
+                .build().unwrap();
+            let mut events = c.events();
+            c.connect().await.unwrap();
+            let error = timeout(Duration::from_secs(1), async {
+                loop {
+                    if let Some(ConnectionEvent::ConnectFailed { error }) = events.next().await {
+                        return error;
+                    }
+                }
+            }).await.expect("No ConnectFailed event");
+            assert!(matches!(*error, Error::Timeout { operation: "connecting", .. }),
+                    "{:?}", error);
+        })
+    }
+
+    #[test]
+    fn malformed_packet_reconnects() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_connect_retry_delay(Duration::from_millis(10))
+                .build().unwrap();
+            let mut events = c.events();
+            c.connect().await.unwrap();
+            let mut conn = broker.accept(false).await;
+            // A remaining length with a fifth continuation byte.
+            conn.write_bytes(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]).await;
+            let _conn = broker.accept(false).await;
+            let closed = timeout(Duration::from_secs(1), async {
+                loop {
+                    if let Some(ConnectionEvent::Disconnected { reason }) = events.next().await {
+                        return reason;
+                    }
+                }
+            }).await.expect("No Disconnected event");
+            assert!(matches!(closed, DisconnectReason::ProtocolError), "{:?}", closed);
+        })
+    }
+
+    #[test]
+    fn client_packet_from_server_reconnects() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = Client::builder()
+                .set_host("fake".to_owned())
+                .set_connector(connector)
+                .set_connect_retry_delay(Duration::from_millis(10))
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let mut conn = broker.accept(false).await;
+            // Only clients send Pingreq.
+            conn.write(&Packet::Pingreq).await;
+            let _conn = broker.accept(false).await;
+        })
+    }
 }
@@ -0,0 +1,70 @@
+//! Encoding and decoding packets for each supported protocol version.
+
+mod v5;
+
+use bytes::BytesMut;
+use crate::{
+    client::value_types::{
+        Properties,
+        ProtocolVersion,
+        ReasonCode,
+    },
+    Result,
+};
+use mqttrs::Packet;
+
+/// A packet together with the MQTT 5 reason codes and properties that
+/// `mqttrs::Packet` has no room for.
+///
+/// With MQTT 3.1.1 `reason_codes`, `properties` and `will_properties`
+/// are always empty.
+#[derive(Clone, Debug)]
+pub(crate) struct Envelope {
+    pub(crate) packet: Packet,
+
+    /// One code for acknowledgements other than Suback and Unsuback,
+    /// Connack and Disconnect. One code per topic for Suback and Unsuback.
+    pub(crate) reason_codes: Vec<ReasonCode>,
+
+    pub(crate) properties: Properties,
+
+    /// The last will's properties, for a Connect packet with a last will.
+    pub(crate) will_properties: Properties,
+}
+
+impl Envelope {
+    /// Returns the first reason code that indicates failure, if any.
+    pub(crate) fn failure(&self) -> Option<ReasonCode> {
+        self.reason_codes.iter().find(|rc| !rc.is_success()).copied()
+    }
+}
+
+impl From<Packet> for Envelope {
+    fn from(packet: Packet) -> Envelope {
+        Envelope {
+            packet,
+            reason_codes: vec![],
+            properties: Properties::default(),
+            will_properties: Properties::default(),
+        }
+    }
+}
+
+/// Encode `e` onto the end of `buf`.
+pub(crate) fn encode(e: &Envelope, version: ProtocolVersion, buf: &mut BytesMut) -> Result<()> {
+    match version {
+        ProtocolVersion::V311 => Ok(mqttrs::encode(&e.packet, buf)?),
+        ProtocolVersion::V5 => v5::encode(e, buf),
+    }
+}
+
+/// Decode a packet from the start of `buf`.
+///
+/// Returns `Ok(None)` and leaves `buf` unchanged if it does not yet
+/// hold a whole packet, otherwise removes the packet's bytes from `buf`.
+pub(crate) fn decode(buf: &mut BytesMut, version: ProtocolVersion) -> Result<Option<Envelope>> {
+    match version {
+        ProtocolVersion::V311 => Ok(mqttrs::decode(buf)?.map(Envelope::from)),
+        ProtocolVersion::V5 => v5::decode(buf),
+    }
+}
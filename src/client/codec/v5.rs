@@ -0,0 +1,841 @@
+//! An MQTT 5.0 codec for the packets a client sends and receives.
+//!
+//! See <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html>
+
+use bytes::{BufMut, BytesMut};
+use crate::{
+    client::{
+        codec::Envelope,
+        value_types::{
+            Properties,
+            ReasonCode,
+        },
+    },
+    Error,
+    Result,
+};
+use mqttrs::{
+    ConnectReturnCode,
+    Packet,
+    Pid,
+    QoS,
+    QosPid,
+    SubscribeReturnCodes,
+};
+
+/// The largest value a variable byte integer can hold.
+const MAX_VARINT: usize = 268_435_455;
+
+const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+const CONTENT_TYPE: u8 = 0x03;
+const RESPONSE_TOPIC: u8 = 0x08;
+const CORRELATION_DATA: u8 = 0x09;
+const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+const SERVER_KEEP_ALIVE: u8 = 0x13;
+const AUTHENTICATION_METHOD: u8 = 0x15;
+const AUTHENTICATION_DATA: u8 = 0x16;
+const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+const WILL_DELAY_INTERVAL: u8 = 0x18;
+const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+const RESPONSE_INFORMATION: u8 = 0x1A;
+const SERVER_REFERENCE: u8 = 0x1C;
+const REASON_STRING: u8 = 0x1F;
+const RECEIVE_MAXIMUM: u8 = 0x21;
+const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+const TOPIC_ALIAS: u8 = 0x23;
+const MAXIMUM_QOS: u8 = 0x24;
+const RETAIN_AVAILABLE: u8 = 0x25;
+const USER_PROPERTY: u8 = 0x26;
+const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+
+/// Encode a packet sent by a client onto the end of `buf`.
+pub(super) fn encode(e: &Envelope, buf: &mut BytesMut) -> Result<()> {
+    let mut body = Vec::new();
+    let header: u8 = match e.packet {
+        Packet::Connect(ref c) => {
+            write_string("MQTT", &mut body)?;
+            body.push(5);
+            let mut flags = 0u8;
+            if c.username.is_some() {
+                flags |= 0x80;
+            }
+            if c.password.is_some() {
+                flags |= 0x40;
+            }
+            if let Some(ref lw) = c.last_will {
+                flags |= 0x04 | (qos_to_u8(lw.qos) << 3);
+                if lw.retain {
+                    flags |= 0x20;
+                }
+            }
+            if c.clean_session {
+                flags |= 0x02;
+            }
+            body.push(flags);
+            body.extend_from_slice(&c.keep_alive.to_be_bytes());
+            write_properties(&e.properties, &mut body)?;
+            write_string(&c.client_id, &mut body)?;
+            if let Some(ref lw) = c.last_will {
+                write_properties(&e.will_properties, &mut body)?;
+                write_string(&lw.topic, &mut body)?;
+                write_binary(&lw.message, &mut body)?;
+            }
+            if let Some(ref u) = c.username {
+                write_string(u, &mut body)?;
+            }
+            if let Some(ref p) = c.password {
+                write_binary(p, &mut body)?;
+            }
+            0x10
+        },
+        Packet::Publish(ref p) => {
+            write_string(&p.topic_name, &mut body)?;
+            if let Some(pid) = p.qospid.pid() {
+                body.extend_from_slice(&pid.get().to_be_bytes());
+            }
+            write_properties(&e.properties, &mut body)?;
+            body.extend_from_slice(&p.payload);
+            0x30 | ((p.dup as u8) << 3) | (qos_to_u8(p.qospid.qos()) << 1) | (p.retain as u8)
+        },
+        Packet::Puback(pid) => {
+            write_ack(pid, e, &mut body)?;
+            0x40
+        },
+        Packet::Pubrec(pid) => {
+            write_ack(pid, e, &mut body)?;
+            0x50
+        },
+        Packet::Pubrel(pid) => {
+            write_ack(pid, e, &mut body)?;
+            0x62
+        },
+        Packet::Pubcomp(pid) => {
+            write_ack(pid, e, &mut body)?;
+            0x70
+        },
+        Packet::Subscribe(ref s) => {
+            body.extend_from_slice(&s.pid.get().to_be_bytes());
+            write_properties(&e.properties, &mut body)?;
+            for t in s.topics.iter() {
+                write_string(&t.topic_path, &mut body)?;
+                body.push(qos_to_u8(t.qos));
+            }
+            0x82
+        },
+        Packet::Unsubscribe(ref u) => {
+            body.extend_from_slice(&u.pid.get().to_be_bytes());
+            write_properties(&e.properties, &mut body)?;
+            for t in u.topics.iter() {
+                write_string(t, &mut body)?;
+            }
+            0xA2
+        },
+        Packet::Pingreq => 0xC0,
+        Packet::Disconnect => {
+            let rc = e.reason_codes.first().copied().unwrap_or(ReasonCode::Success);
+            if rc != ReasonCode::Success || !e.properties.is_empty() {
+                body.push(rc.to_u8());
+                write_properties(&e.properties, &mut body)?;
+            }
+            0xE0
+        },
+        Packet::Connack(_) |
+        Packet::Suback(_) |
+        Packet::Unsuback(_) |
+        Packet::Pingresp => {
+            return Err(format!("Encoding MQTT 5 packet not supported: {:?}",
+                               e.packet.get_type()).into());
+        },
+    };
+    if body.len() > MAX_VARINT {
+        return Err(violation(format!("a packet of at most {} bytes", MAX_VARINT),
+                             format!("a packet of {} bytes", body.len())));
+    }
+    buf.reserve(5 + body.len());
+    buf.put_u8(header);
+    let mut len = Vec::with_capacity(4);
+    write_varint(body.len(), &mut len);
+    buf.put_slice(&len);
+    buf.put_slice(&body);
+    Ok(())
+}
+
+/// Decode a packet sent by a server from the start of `buf`.
+pub(super) fn decode(buf: &mut BytesMut) -> Result<Option<Envelope>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    // Read the remaining length from the fixed header.
+    let mut remaining_len = 0usize;
+    let mut header_len = None;
+    for i in 0..4 {
+        let byte = match buf.get(1 + i) {
+            Some(b) => *b,
+            None => return Ok(None),
+        };
+        remaining_len |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            header_len = Some(2 + i);
+            break;
+        }
+    }
+    let header_len = match header_len {
+        Some(l) => l,
+        None => return Err(malformed("remaining length")),
+    };
+    if buf.len() < header_len + remaining_len {
+        return Ok(None);
+    }
+    let bytes = buf.split_to(header_len + remaining_len);
+    let header = bytes[0];
+    let mut r = Reader { buf: &bytes[header_len..] };
+    let e = match header >> 4 {
+        2 => {
+            let session_present = r.u8()? & 0x01 == 0x01;
+            let rc = r.reason_code()?;
+            let properties = r.properties()?;
+            Envelope {
+                packet: Packet::Connack(mqttrs::Connack {
+                    session_present,
+                    code: connect_return_code(rc),
+                }),
+                reason_codes: vec![rc],
+                properties,
+                will_properties: Properties::default(),
+            }
+        },
+        3 => {
+            let dup = header & 0x08 == 0x08;
+            let retain = header & 0x01 == 0x01;
+            let topic_name = r.string()?;
+            let qospid = match (header >> 1) & 0x03 {
+                0 => QosPid::AtMostOnce,
+                1 => QosPid::AtLeastOnce(r.pid()?),
+                2 => QosPid::ExactlyOnce(r.pid()?),
+                _ => return Err(malformed("Publish QoS")),
+            };
+            let properties = r.properties()?;
+            Envelope {
+                packet: Packet::Publish(mqttrs::Publish {
+                    dup,
+                    qospid,
+                    retain,
+                    topic_name,
+                    payload: r.rest().to_vec(),
+                }),
+                reason_codes: vec![],
+                properties,
+                will_properties: Properties::default(),
+            }
+        },
+        4 => r.ack(Packet::Puback)?,
+        5 => r.ack(Packet::Pubrec)?,
+        6 => r.ack(Packet::Pubrel)?,
+        7 => r.ack(Packet::Pubcomp)?,
+        9 => {
+            let pid = r.pid()?;
+            let properties = r.properties()?;
+            let mut reason_codes = vec![];
+            while !r.buf.is_empty() {
+                reason_codes.push(r.reason_code()?);
+            }
+            let return_codes = reason_codes.iter().map(|rc| match rc {
+                ReasonCode::Success => SubscribeReturnCodes::Success(QoS::AtMostOnce),
+                ReasonCode::GrantedQoS1 => SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                ReasonCode::GrantedQoS2 => SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+                _ => SubscribeReturnCodes::Failure,
+            }).collect();
+            Envelope {
+                packet: Packet::Suback(mqttrs::Suback { pid, return_codes }),
+                reason_codes,
+                properties,
+                will_properties: Properties::default(),
+            }
+        },
+        11 => {
+            let pid = r.pid()?;
+            let properties = r.properties()?;
+            let mut reason_codes = vec![];
+            while !r.buf.is_empty() {
+                reason_codes.push(r.reason_code()?);
+            }
+            Envelope {
+                packet: Packet::Unsuback(pid),
+                reason_codes,
+                properties,
+                will_properties: Properties::default(),
+            }
+        },
+        13 => Envelope::from(Packet::Pingresp),
+        14 => {
+            let rc = if r.buf.is_empty() {
+                ReasonCode::Success
+            } else {
+                r.reason_code()?
+            };
+            let properties = if r.buf.is_empty() {
+                Properties::default()
+            } else {
+                r.properties()?
+            };
+            Envelope {
+                packet: Packet::Disconnect,
+                reason_codes: vec![rc],
+                properties,
+                will_properties: Properties::default(),
+            }
+        },
+        15 => return Err(violation("no Auth packet, which is not supported", "Auth")),
+        t => return Err(violation("a packet a server may send",
+                                  format!("packet type {}", t))),
+    };
+    Ok(Some(e))
+}
+
+/// An error for a packet that breaks the MQTT 5 protocol.
+fn violation<E: Into<String>, G: Into<String>>(expected: E, got: G) -> Error {
+    Error::ProtocolViolation {
+        expected: expected.into(),
+        got: got.into(),
+    }
+}
+
+fn malformed(what: &str) -> Error {
+    violation("a well-formed MQTT 5 packet", format!("a bad {}", what))
+}
+
+fn qos_to_u8(qos: QoS) -> u8 {
+    match qos {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    }
+}
+
+fn connect_return_code(rc: ReasonCode) -> ConnectReturnCode {
+    match rc {
+        ReasonCode::Success => ConnectReturnCode::Accepted,
+        ReasonCode::UnsupportedProtocolVersion => ConnectReturnCode::RefusedProtocolVersion,
+        ReasonCode::ClientIdentifierNotValid => ConnectReturnCode::RefusedIdentifierRejected,
+        ReasonCode::BadUserNameOrPassword => ConnectReturnCode::BadUsernamePassword,
+        ReasonCode::NotAuthorized => ConnectReturnCode::NotAuthorized,
+        _ => ConnectReturnCode::ServerUnavailable,
+    }
+}
+
+/// Write the body of a Puback, Pubrec, Pubrel or Pubcomp packet,
+/// omitting the reason code and properties when they have default values.
+fn write_ack(pid: Pid, e: &Envelope, body: &mut Vec<u8>) -> Result<()> {
+    body.extend_from_slice(&pid.get().to_be_bytes());
+    let rc = e.reason_codes.first().copied().unwrap_or(ReasonCode::Success);
+    if rc != ReasonCode::Success || !e.properties.is_empty() {
+        body.push(rc.to_u8());
+        if !e.properties.is_empty() {
+            write_properties(&e.properties, body)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_varint(mut v: usize, body: &mut Vec<u8>) {
+    loop {
+        let mut byte = (v % 128) as u8;
+        v /= 128;
+        if v > 0 {
+            byte |= 0x80;
+        }
+        body.push(byte);
+        if v == 0 {
+            return;
+        }
+    }
+}
+
+fn write_string(s: &str, body: &mut Vec<u8>) -> Result<()> {
+    write_binary(s.as_bytes(), body)
+}
+
+fn write_binary(b: &[u8], body: &mut Vec<u8>) -> Result<()> {
+    if b.len() > u16::MAX as usize {
+        return Err(format!("MQTT 5 string or binary data too long: {} bytes", b.len()).into());
+    }
+    body.extend_from_slice(&(b.len() as u16).to_be_bytes());
+    body.extend_from_slice(b);
+    Ok(())
+}
+
+fn write_properties(p: &Properties, body: &mut Vec<u8>) -> Result<()> {
+    let mut props = Vec::new();
+    let w = &mut props;
+    if let Some(v) = p.payload_format_indicator {
+        w.extend_from_slice(&[PAYLOAD_FORMAT_INDICATOR, v]);
+    }
+    if let Some(v) = p.message_expiry_interval {
+        w.push(MESSAGE_EXPIRY_INTERVAL);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(ref v) = p.content_type {
+        w.push(CONTENT_TYPE);
+        write_string(v, w)?;
+    }
+    if let Some(ref v) = p.response_topic {
+        w.push(RESPONSE_TOPIC);
+        write_string(v, w)?;
+    }
+    if let Some(ref v) = p.correlation_data {
+        w.push(CORRELATION_DATA);
+        write_binary(v, w)?;
+    }
+    for v in p.subscription_identifiers.iter() {
+        w.push(SUBSCRIPTION_IDENTIFIER);
+        write_varint(*v as usize, w);
+    }
+    if let Some(v) = p.session_expiry_interval {
+        w.push(SESSION_EXPIRY_INTERVAL);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(ref v) = p.assigned_client_identifier {
+        w.push(ASSIGNED_CLIENT_IDENTIFIER);
+        write_string(v, w)?;
+    }
+    if let Some(v) = p.server_keep_alive {
+        w.push(SERVER_KEEP_ALIVE);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(ref v) = p.authentication_method {
+        w.push(AUTHENTICATION_METHOD);
+        write_string(v, w)?;
+    }
+    if let Some(ref v) = p.authentication_data {
+        w.push(AUTHENTICATION_DATA);
+        write_binary(v, w)?;
+    }
+    if let Some(v) = p.request_problem_information {
+        w.extend_from_slice(&[REQUEST_PROBLEM_INFORMATION, v]);
+    }
+    if let Some(v) = p.will_delay_interval {
+        w.push(WILL_DELAY_INTERVAL);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(v) = p.request_response_information {
+        w.extend_from_slice(&[REQUEST_RESPONSE_INFORMATION, v]);
+    }
+    if let Some(ref v) = p.response_information {
+        w.push(RESPONSE_INFORMATION);
+        write_string(v, w)?;
+    }
+    if let Some(ref v) = p.server_reference {
+        w.push(SERVER_REFERENCE);
+        write_string(v, w)?;
+    }
+    if let Some(ref v) = p.reason_string {
+        w.push(REASON_STRING);
+        write_string(v, w)?;
+    }
+    if let Some(v) = p.receive_maximum {
+        w.push(RECEIVE_MAXIMUM);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(v) = p.topic_alias_maximum {
+        w.push(TOPIC_ALIAS_MAXIMUM);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(v) = p.topic_alias {
+        w.push(TOPIC_ALIAS);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(v) = p.maximum_qos {
+        w.extend_from_slice(&[MAXIMUM_QOS, v]);
+    }
+    if let Some(v) = p.retain_available {
+        w.extend_from_slice(&[RETAIN_AVAILABLE, v]);
+    }
+    for (k, v) in p.user_properties.iter() {
+        w.push(USER_PROPERTY);
+        write_string(k, w)?;
+        write_string(v, w)?;
+    }
+    if let Some(v) = p.maximum_packet_size {
+        w.push(MAXIMUM_PACKET_SIZE);
+        w.extend_from_slice(&v.to_be_bytes());
+    }
+    if let Some(v) = p.wildcard_subscription_available {
+        w.extend_from_slice(&[WILDCARD_SUBSCRIPTION_AVAILABLE, v]);
+    }
+    if let Some(v) = p.subscription_identifier_available {
+        w.extend_from_slice(&[SUBSCRIPTION_IDENTIFIER_AVAILABLE, v]);
+    }
+    if let Some(v) = p.shared_subscription_available {
+        w.extend_from_slice(&[SHARED_SUBSCRIPTION_AVAILABLE, v]);
+    }
+    write_varint(props.len(), body);
+    body.extend_from_slice(&props);
+    Ok(())
+}
+
+/// Reads values from the variable header and payload of a packet.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(malformed("length"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.buf;
+        self.buf = &[];
+        rest
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let mut v = 0usize;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            v |= ((byte & 0x7F) as usize) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(malformed("variable byte integer"))
+    }
+
+    fn binary(&mut self) -> Result<Vec<u8>> {
+        let len = self.u16()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        String::from_utf8(self.binary()?).map_err(|_| malformed("UTF-8 string"))
+    }
+
+    fn pid(&mut self) -> Result<Pid> {
+        Pid::try_from(self.u16()?).map_err(|_| malformed("packet identifier"))
+    }
+
+    fn reason_code(&mut self) -> Result<ReasonCode> {
+        let v = self.u8()?;
+        ReasonCode::from_u8(v)
+            .ok_or_else(|| malformed(&format!("reason code {:#04x}", v)))
+    }
+
+    /// Read a Puback, Pubrec, Pubrel or Pubcomp body.
+    fn ack(&mut self, packet: fn(Pid) -> Packet) -> Result<Envelope> {
+        let pid = self.pid()?;
+        let rc = if self.buf.is_empty() {
+            ReasonCode::Success
+        } else {
+            self.reason_code()?
+        };
+        let properties = if self.buf.is_empty() {
+            Properties::default()
+        } else {
+            self.properties()?
+        };
+        Ok(Envelope {
+            packet: packet(pid),
+            reason_codes: vec![rc],
+            properties,
+            will_properties: Properties::default(),
+        })
+    }
+
+    fn properties(&mut self) -> Result<Properties> {
+        let len = self.varint()?;
+        let mut r = Reader { buf: self.take(len)? };
+        let mut p = Properties::default();
+        while !r.buf.is_empty() {
+            match r.u8()? {
+                PAYLOAD_FORMAT_INDICATOR => p.payload_format_indicator = Some(r.u8()?),
+                MESSAGE_EXPIRY_INTERVAL => p.message_expiry_interval = Some(r.u32()?),
+                CONTENT_TYPE => p.content_type = Some(r.string()?),
+                RESPONSE_TOPIC => p.response_topic = Some(r.string()?),
+                CORRELATION_DATA => p.correlation_data = Some(r.binary()?),
+                SUBSCRIPTION_IDENTIFIER => p.subscription_identifiers.push(r.varint()? as u32),
+                SESSION_EXPIRY_INTERVAL => p.session_expiry_interval = Some(r.u32()?),
+                ASSIGNED_CLIENT_IDENTIFIER => p.assigned_client_identifier = Some(r.string()?),
+                SERVER_KEEP_ALIVE => p.server_keep_alive = Some(r.u16()?),
+                AUTHENTICATION_METHOD => p.authentication_method = Some(r.string()?),
+                AUTHENTICATION_DATA => p.authentication_data = Some(r.binary()?),
+                REQUEST_PROBLEM_INFORMATION => p.request_problem_information = Some(r.u8()?),
+                WILL_DELAY_INTERVAL => p.will_delay_interval = Some(r.u32()?),
+                REQUEST_RESPONSE_INFORMATION => p.request_response_information = Some(r.u8()?),
+                RESPONSE_INFORMATION => p.response_information = Some(r.string()?),
+                SERVER_REFERENCE => p.server_reference = Some(r.string()?),
+                REASON_STRING => p.reason_string = Some(r.string()?),
+                RECEIVE_MAXIMUM => p.receive_maximum = Some(r.u16()?),
+                TOPIC_ALIAS_MAXIMUM => p.topic_alias_maximum = Some(r.u16()?),
+                TOPIC_ALIAS => p.topic_alias = Some(r.u16()?),
+                MAXIMUM_QOS => p.maximum_qos = Some(r.u8()?),
+                RETAIN_AVAILABLE => p.retain_available = Some(r.u8()?),
+                USER_PROPERTY => {
+                    let k = r.string()?;
+                    let v = r.string()?;
+                    p.user_properties.push((k, v));
+                },
+                MAXIMUM_PACKET_SIZE => p.maximum_packet_size = Some(r.u32()?),
+                WILDCARD_SUBSCRIPTION_AVAILABLE =>
+                    p.wildcard_subscription_available = Some(r.u8()?),
+                SUBSCRIPTION_IDENTIFIER_AVAILABLE =>
+                    p.subscription_identifier_available = Some(r.u8()?),
+                SHARED_SUBSCRIPTION_AVAILABLE => p.shared_subscription_available = Some(r.u8()?),
+                id => return Err(malformed(&format!("property {:#04x}", id))),
+            }
+        }
+        Ok(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::client::{
+        codec::Envelope,
+        Properties,
+        ReasonCode,
+    };
+    use mqttrs::{
+        Packet,
+        Pid,
+        QoS,
+        QosPid,
+        SubscribeReturnCodes,
+        SubscribeTopic,
+    };
+    use crate::Error;
+    use super::{decode, encode};
+
+    fn pid(p: u16) -> Pid {
+        Pid::try_from(p).unwrap()
+    }
+
+    fn encoded(e: &Envelope) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        encode(e, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn decoded(bytes: &[u8]) -> Envelope {
+        let mut buf = BytesMut::from(bytes);
+        let e = decode(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        e
+    }
+
+    #[test]
+    fn connect() {
+        let mut e = Envelope::from(Packet::Connect(mqttrs::Connect {
+            protocol: mqttrs::Protocol::MQTT311,
+            keep_alive: 30,
+            client_id: "c".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: Some("u".to_owned()),
+            password: Some(b"p".to_vec()),
+        }));
+        e.properties.session_expiry_interval = Some(10);
+        assert_eq!(encoded(&e), vec![
+            0x10, 25,
+            0, 4, b'M', b'Q', b'T', b'T', 5,
+            0xC2, 0, 30,
+            5, 0x11, 0, 0, 0, 10,
+            0, 1, b'c',
+            0, 1, b'u',
+            0, 1, b'p',
+        ]);
+    }
+
+    #[test]
+    fn connect_with_will() {
+        let mut e = Envelope::from(Packet::Connect(mqttrs::Connect {
+            protocol: mqttrs::Protocol::MQTT311,
+            keep_alive: 30,
+            client_id: "c".to_owned(),
+            clean_session: true,
+            last_will: Some(mqttrs::LastWill {
+                topic: "w".to_owned(),
+                message: b"m".to_vec(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            username: None,
+            password: None,
+        }));
+        e.will_properties.will_delay_interval = Some(5);
+        assert_eq!(encoded(&e), vec![
+            0x10, 26,
+            0, 4, b'M', b'Q', b'T', b'T', 5,
+            0x2E, 0, 30,
+            0,
+            0, 1, b'c',
+            5, 0x18, 0, 0, 0, 5,
+            0, 1, b'w',
+            0, 1, b'm',
+        ]);
+    }
+
+    #[test]
+    fn connack() {
+        let e = decoded(&[0x20, 6, 0x01, 0x00, 3, 0x13, 0, 60]);
+        match e.packet {
+            Packet::Connack(ca) => {
+                assert!(ca.session_present);
+                assert_eq!(ca.code, mqttrs::ConnectReturnCode::Accepted);
+            },
+            _ => panic!("Expected Connack"),
+        }
+        assert_eq!(e.reason_codes, vec![ReasonCode::Success]);
+        assert_eq!(e.properties.server_keep_alive, Some(60));
+
+        let e = decoded(&[0x20, 3, 0x00, 0x87, 0]);
+        assert_eq!(e.failure(), Some(ReasonCode::NotAuthorized));
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let mut e = Envelope::from(Packet::Publish(mqttrs::Publish {
+            dup: true,
+            qospid: QosPid::ExactlyOnce(pid(7)),
+            retain: true,
+            topic_name: "a/b".to_owned(),
+            payload: b"hello".to_vec(),
+        }));
+        e.properties = Properties {
+            message_expiry_interval: Some(60),
+            content_type: Some("text/plain".to_owned()),
+            response_topic: Some("a/reply".to_owned()),
+            correlation_data: Some(vec![1, 2, 3]),
+            topic_alias: Some(3),
+            user_properties: vec![("k".to_owned(), "v".to_owned()),
+                                  ("k".to_owned(), "w".to_owned())],
+            .. Properties::default()
+        };
+        let bytes = encoded(&e);
+        assert_eq!(bytes[0], 0x3D);
+        let d = decoded(&bytes);
+        assert_eq!(d.packet, e.packet);
+        assert_eq!(d.properties, e.properties);
+    }
+
+    #[test]
+    fn acks() {
+        // Short form: reason code Success, no properties.
+        let e = Envelope::from(Packet::Puback(pid(1)));
+        assert_eq!(encoded(&e), vec![0x40, 2, 0, 1]);
+        let d = decoded(&[0x40, 2, 0, 1]);
+        assert_eq!(d.packet, Packet::Puback(pid(1)));
+        assert_eq!(d.reason_codes, vec![ReasonCode::Success]);
+
+        // Reason code without properties.
+        let d = decoded(&[0x50, 3, 0, 2, 0x10]);
+        assert_eq!(d.packet, Packet::Pubrec(pid(2)));
+        assert_eq!(d.failure(), None);
+
+        // Reason code and properties.
+        let d = decoded(&[0x70, 8, 0, 3, 0x92, 4, 0x1F, 0, 1, b'x']);
+        assert_eq!(d.packet, Packet::Pubcomp(pid(3)));
+        assert_eq!(d.failure(), Some(ReasonCode::PacketIdentifierNotFound));
+        assert_eq!(d.properties.reason_string, Some("x".to_owned()));
+
+        let e = Envelope::from(Packet::Pubrel(pid(4)));
+        assert_eq!(encoded(&e), vec![0x62, 2, 0, 4]);
+    }
+
+    #[test]
+    fn subscribe_and_suback() {
+        let e = Envelope::from(Packet::Subscribe(mqttrs::Subscribe {
+            pid: pid(5),
+            topics: vec![SubscribeTopic { topic_path: "a".to_owned(), qos: QoS::ExactlyOnce }],
+        }));
+        assert_eq!(encoded(&e), vec![0x82, 7, 0, 5, 0, 0, 1, b'a', 2]);
+
+        let d = decoded(&[0x90, 5, 0, 5, 0, 0x02, 0x8F]);
+        match d.packet {
+            Packet::Suback(ref s) => {
+                assert_eq!(s.pid, pid(5));
+                assert_eq!(s.return_codes,
+                           vec![SubscribeReturnCodes::Success(QoS::ExactlyOnce),
+                                SubscribeReturnCodes::Failure]);
+            },
+            _ => panic!("Expected Suback"),
+        }
+        assert_eq!(d.reason_codes, vec![ReasonCode::GrantedQoS2,
+                                        ReasonCode::TopicFilterInvalid]);
+    }
+
+    #[test]
+    fn unsubscribe_and_unsuback() {
+        let e = Envelope::from(Packet::Unsubscribe(mqttrs::Unsubscribe {
+            pid: pid(6),
+            topics: vec!["a".to_owned()],
+        }));
+        assert_eq!(encoded(&e), vec![0xA2, 6, 0, 6, 0, 0, 1, b'a']);
+
+        let d = decoded(&[0xB0, 4, 0, 6, 0, 0x11]);
+        assert_eq!(d.packet, Packet::Unsuback(pid(6)));
+        assert_eq!(d.reason_codes, vec![ReasonCode::NoSubscriptionExisted]);
+    }
+
+    #[test]
+    fn disconnect() {
+        let e = Envelope::from(Packet::Disconnect);
+        assert_eq!(encoded(&e), vec![0xE0, 0]);
+
+        let d = decoded(&[0xE0, 0]);
+        assert_eq!(d.reason_codes, vec![ReasonCode::Success]);
+
+        let d = decoded(&[0xE0, 1, 0x8B]);
+        assert_eq!(d.failure(), Some(ReasonCode::ServerShuttingDown));
+    }
+
+    #[test]
+    fn incomplete() {
+        let mut buf = BytesMut::from(&[0x30, 10, 0, 1][..]);
+        assert!(decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 4);
+
+        let mut buf = BytesMut::from(&[0x30][..]);
+        assert!(decode(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed() {
+        // Unknown property identifier.
+        let mut buf = BytesMut::from(&[0x40, 5, 0, 1, 0, 1, 0x7F][..]);
+        assert!(matches!(decode(&mut buf), Err(Error::ProtocolViolation { .. })));
+
+        // Remaining length longer than 4 bytes.
+        let mut buf = BytesMut::from(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x01][..]);
+        assert!(matches!(decode(&mut buf), Err(Error::ProtocolViolation { .. })));
+
+        // A Connect, which only a client sends.
+        let mut buf = BytesMut::from(&[0x10, 0][..]);
+        assert!(matches!(decode(&mut buf), Err(Error::ProtocolViolation { .. })));
+    }
+}
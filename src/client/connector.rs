@@ -0,0 +1,220 @@
+use crate::{
+    client::Endpoint,
+    Result,
+};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// A connection to a broker, returned by a `Connector`.
+///
+/// Implemented for every `AsyncRead + AsyncWrite + Unpin + Send` type,
+/// e.g. `tokio::net::TcpStream` or one end of a `tokio::io::duplex`
+/// pipe.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// The future returned by `Connector::connect`.
+pub type ConnectFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Box<dyn Transport>>> + Send + 'a>>;
+
+/// Opens connections to brokers, see `ClientBuilder::set_connector`.
+///
+/// The client calls the connector for every connection attempt, then
+/// runs TLS and WebSocket, if configured, and MQTT over the connection
+/// it returns. Reconnects, keep alive and subscription replay work the
+/// same whatever the connector.
+pub trait Connector: Debug + Send + Sync {
+    /// Open a connection to `endpoint`.
+    fn connect<'a>(&'a self, endpoint: &'a Endpoint) -> ConnectFuture<'a>;
+}
+
+/// Opens a TCP connection to the endpoint's host and port.
+///
+/// This is the default connector.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpConnector;
+
+impl TcpConnector {
+    /// Construct a new instance.
+    pub fn new() -> TcpConnector {
+        TcpConnector
+    }
+}
+
+impl Connector for TcpConnector {
+    fn connect<'a>(&'a self, endpoint: &'a Endpoint) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let tcp = TcpStream::connect((endpoint.host(), endpoint.port())).await?;
+            Ok(Box::new(tcp) as Box<dyn Transport>)
+        })
+    }
+}
+
+/// Opens a Unix domain socket connection to a path, e.g. to a broker
+/// on the same host. The endpoint's host and port are not used to
+/// connect.
+#[cfg(unix)]
+#[derive(Clone, Debug)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixConnector {
+    /// Construct a new instance to connect to the socket at `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixConnector {
+        UnixConnector { path: path.into() }
+    }
+
+    /// Returns the path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl Connector for UnixConnector {
+    fn connect<'a>(&'a self, _endpoint: &'a Endpoint) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let stream = tokio::net::UnixStream::connect(&self.path).await?;
+            Ok(Box::new(stream) as Box<dyn Transport>)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{Client, Publish, QoS};
+    use bytes::BytesMut;
+    use mqttrs::{Connack, ConnectReturnCode, Packet, QosPid};
+    use std::sync::Mutex;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        time::Duration,
+    };
+
+    /// Hands out one end of an in-memory pipe.
+    #[derive(Debug)]
+    struct DuplexConnector(Mutex<Option<DuplexStream>>);
+
+    impl Connector for DuplexConnector {
+        fn connect<'a>(&'a self, _endpoint: &'a Endpoint) -> ConnectFuture<'a> {
+            let stream = self.0.lock().unwrap().take();
+            Box::pin(async move {
+                let stream = stream.ok_or("Already connected")?;
+                Ok(Box::new(stream) as Box<dyn Transport>)
+            })
+        }
+    }
+
+    async fn read_packet<S: Transport>(s: &mut S, buf: &mut BytesMut) -> Packet {
+        loop {
+            if let Some(p) = mqttrs::decode(buf).unwrap() {
+                return p;
+            }
+            let mut b = [0u8; 1024];
+            let n = s.read(&mut b).await.unwrap();
+            assert!(n > 0, "Connection closed");
+            buf.extend_from_slice(&b[..n]);
+        }
+    }
+
+    async fn write_packet<S: Transport>(s: &mut S, p: &Packet) {
+        let mut buf = BytesMut::with_capacity(1024);
+        mqttrs::encode(p, &mut buf).unwrap();
+        s.write_all(&buf).await.unwrap();
+    }
+
+    #[test]
+    fn custom_connector() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client_end, mut broker) = tokio::io::duplex(1024);
+            let mut c = Client::builder()
+                .set_host("in-memory".to_owned())
+                .set_connector(DuplexConnector(Mutex::new(Some(client_end))))
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let broker = tokio::spawn(async move {
+                let mut buf = BytesMut::new();
+                assert!(matches!(read_packet(&mut broker, &mut buf).await, Packet::Connect(_)));
+                write_packet(&mut broker, &Packet::Connack(Connack {
+                    session_present: false,
+                    code: ConnectReturnCode::Accepted,
+                })).await;
+                match read_packet(&mut broker, &mut buf).await {
+                    Packet::Publish(mqttrs::Publish {
+                        qospid: QosPid::AtLeastOnce(pid), topic_name, ..
+                    }) => {
+                        assert_eq!(topic_name, "a");
+                        write_packet(&mut broker, &Packet::Puback(pid)).await;
+                    },
+                    p => panic!("Unexpected packet {:?}", p),
+                }
+                broker
+            });
+            let mut p = Publish::new("a".to_owned(), b"x".to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            c.publish(&p).await.unwrap();
+            let _broker = broker.await.unwrap();
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_reconnects() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let path = std::env::temp_dir()
+                .join(format!("mqtt-async-client-test-{}.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+            let mut c = Client::builder()
+                .set_unix_socket_path(path.clone())
+                .set_connect_retry_delay(Duration::from_millis(10))
+                .build().unwrap();
+            c.connect().await.unwrap();
+            let connack = Packet::Connack(Connack {
+                session_present: false,
+                code: ConnectReturnCode::Accepted,
+            });
+            let broker = tokio::spawn(async move {
+                // Accept then drop the first connection, so the client
+                // reconnects.
+                let (mut s, _) = listener.accept().await.unwrap();
+                let mut buf = BytesMut::new();
+                assert!(matches!(read_packet(&mut s, &mut buf).await, Packet::Connect(_)));
+                write_packet(&mut s, &connack).await;
+                drop(s);
+
+                let (mut s, _) = listener.accept().await.unwrap();
+                let mut buf = BytesMut::new();
+                assert!(matches!(read_packet(&mut s, &mut buf).await, Packet::Connect(_)));
+                write_packet(&mut s, &connack).await;
+                match read_packet(&mut s, &mut buf).await {
+                    Packet::Publish(mqttrs::Publish {
+                        qospid: QosPid::AtLeastOnce(pid), ..
+                    }) => write_packet(&mut s, &Packet::Puback(pid)).await,
+                    p => panic!("Unexpected packet {:?}", p),
+                }
+                s
+            });
+            let mut p = Publish::new("a".to_owned(), b"x".to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            c.publish(&p).await.unwrap();
+            let _s = broker.await.unwrap();
+            std::fs::remove_file(&path).unwrap();
+        })
+    }
+}
@@ -0,0 +1,92 @@
+//! An in-memory fake broker for unit tests that need to drop and
+//! accept connections.
+
+use crate::client::{
+    ConnectFuture,
+    Connector,
+    Endpoint,
+    Transport,
+};
+use bytes::BytesMut;
+use mqttrs::{Connack, ConnectReturnCode, Packet};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+    time::{Duration, timeout},
+};
+
+/// Opens a new in-memory pipe for each connection attempt, and hands
+/// the other end to the `FakeBroker`.
+#[derive(Debug)]
+pub(crate) struct FakeConnector {
+    tx: mpsc::UnboundedSender<DuplexStream>,
+}
+
+/// The broker side of the connections opened by a `FakeConnector`.
+pub(crate) struct FakeBroker {
+    rx: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+/// One connection to the `FakeBroker`.
+pub(crate) struct FakeConnection {
+    stream: DuplexStream,
+    buf: BytesMut,
+}
+
+pub(crate) fn fake_broker() -> (FakeConnector, FakeBroker) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (FakeConnector { tx }, FakeBroker { rx })
+}
+
+impl Connector for FakeConnector {
+    fn connect<'a>(&'a self, _endpoint: &'a Endpoint) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let (client, broker) = tokio::io::duplex(64 * 1024);
+            self.tx.send(broker).map_err(|_| "FakeBroker dropped")?;
+            Ok(Box::new(client) as Box<dyn Transport>)
+        })
+    }
+}
+
+impl FakeBroker {
+    /// Wait for the next connection, then answer its Connect packet.
+    pub(crate) async fn accept(&mut self, session_present: bool) -> FakeConnection {
+        let stream = timeout(Duration::from_secs(5), self.rx.recv()).await
+            .expect("No connection before timeout")
+            .expect("FakeConnector dropped");
+        let mut c = FakeConnection { stream, buf: BytesMut::new() };
+        assert!(matches!(c.read().await, Packet::Connect(_)));
+        c.write(&Packet::Connack(Connack {
+            session_present,
+            code: ConnectReturnCode::Accepted,
+        })).await;
+        c
+    }
+}
+
+impl FakeConnection {
+    pub(crate) async fn read(&mut self) -> Packet {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(p) = mqttrs::decode(&mut self.buf).unwrap() {
+                    return p;
+                }
+                let mut b = [0u8; 1024];
+                let n = self.stream.read(&mut b).await.unwrap();
+                assert!(n > 0, "Connection closed");
+                self.buf.extend_from_slice(&b[..n]);
+            }
+        }).await.expect("No packet before timeout")
+    }
+
+    pub(crate) async fn write(&mut self, p: &Packet) {
+        let mut buf = BytesMut::with_capacity(1024);
+        mqttrs::encode(p, &mut buf).unwrap();
+        self.write_bytes(&buf).await;
+    }
+
+    /// Write raw bytes, e.g. a malformed packet.
+    pub(crate) async fn write_bytes(&mut self, b: &[u8]) {
+        self.stream.write_all(b).await.unwrap();
+    }
+}
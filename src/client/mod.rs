@@ -4,21 +4,85 @@ mod builder;
 pub use builder::ClientBuilder;
 
 mod client;
-pub use client::Client;
+pub use client::{
+    Client,
+    ClientHandle,
+};
 pub(crate) use client::ClientOptions;
 
+mod codec;
+
+#[cfg(feature = "serde")]
+mod config;
+#[cfg(feature = "serde")]
+pub use config::ClientConfig;
+
+mod connector;
+pub use connector::{
+    ConnectFuture,
+    Connector,
+    TcpConnector,
+    Transport,
+};
+#[cfg(unix)]
+pub use connector::UnixConnector;
+
+#[cfg(test)]
+mod fake_broker;
+
+mod proxy;
+pub use proxy::{
+    Proxy,
+    ProxyConnector,
+    ProxyKind,
+};
+
+mod reconnect;
+pub use reconnect::{
+    DecorrelatedJitter,
+    ExponentialBackoff,
+    FixedDelay,
+    MaxAttempts,
+    ReconnectPolicy,
+};
+
+mod session_store;
+pub use session_store::{
+    FileStore,
+    MemoryStore,
+    OverflowPolicy,
+    SessionStore,
+};
+
+mod subscription;
+pub use subscription::Subscription;
+
 mod value_types;
 pub use value_types::{
+    Ack,
+    ConnectionEvent,
+    DisconnectReason,
+    Endpoint,
+    EndpointPolicy,
     KeepAlive,
+    LastWill,
+    Properties,
+    ProtocolVersion,
     Publish,
     ReadResult,
+    ReasonCode,
     Subscribe,
     SubscribeResult,
     Unsubscribe,
     UnsubscribeTopic,
 };
+#[cfg(feature = "websocket")]
+pub use value_types::WebSocketOptions;
+
+mod write_pids;
 
 pub use mqttrs::{
+    ConnectReturnCode,
     QoS,
     SubscribeReturnCodes,
     SubscribeTopic,
@@ -0,0 +1,176 @@
+use rand::Rng;
+use std::{
+    cmp::{max, min},
+    fmt::Debug,
+    time::Duration,
+};
+
+/// Decides how long to wait between connection attempts, see
+/// `ClientBuilder::set_reconnect_policy`.
+///
+/// The client asks for a delay each time a connection attempt fails
+/// (after trying every endpoint), and starts again from the first
+/// attempt once a connection succeeds. After losing an established
+/// connection it reconnects after a random delay of up to a second, or
+/// up to the delay for the first attempt if that is shorter, and
+/// straight away if that is `None`.
+pub trait ReconnectPolicy: Debug + Send + Sync {
+    /// Returns how long to wait before the next connection attempt,
+    /// or `None` to give up.
+    ///
+    /// `attempt` is the number of consecutive failed attempts,
+    /// starting at 1. `previous` is the delay returned for the
+    /// previous attempt, or zero for the first.
+    fn delay(&self, attempt: u32, previous: Duration) -> Option<Duration>;
+}
+
+/// Wait the same delay after every failed attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct FixedDelay {
+    delay: Duration,
+}
+
+impl FixedDelay {
+    /// Construct a new instance.
+    pub fn new(delay: Duration) -> FixedDelay {
+        FixedDelay { delay }
+    }
+}
+
+impl ReconnectPolicy for FixedDelay {
+    fn delay(&self, _attempt: u32, _previous: Duration) -> Option<Duration> {
+        Some(self.delay)
+    }
+}
+
+/// Double the delay after every failed attempt, starting from
+/// `initial` and limited to `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct ExponentialBackoff {
+    initial: Duration,
+    max: Duration,
+}
+
+impl ExponentialBackoff {
+    /// Construct a new instance.
+    pub fn new(initial: Duration, max: Duration) -> ExponentialBackoff {
+        ExponentialBackoff { initial, max }
+    }
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn delay(&self, attempt: u32, _previous: Duration) -> Option<Duration> {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        Some(min(self.initial.checked_mul(factor).unwrap_or(self.max), self.max))
+    }
+}
+
+/// Choose each delay at random between `base` and three times the
+/// previous delay, limited to `cap`.
+///
+/// The randomness spreads out clients that lost their connections at
+/// the same moment, e.g. when a broker restarts. See "Decorrelated
+/// Jitter" in
+/// [Exponential Backoff And Jitter](https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/).
+#[derive(Clone, Copy, Debug)]
+pub struct DecorrelatedJitter {
+    base: Duration,
+    cap: Duration,
+}
+
+impl DecorrelatedJitter {
+    /// Construct a new instance.
+    pub fn new(base: Duration, cap: Duration) -> DecorrelatedJitter {
+        DecorrelatedJitter { base, cap }
+    }
+}
+
+impl ReconnectPolicy for DecorrelatedJitter {
+    fn delay(&self, _attempt: u32, previous: Duration) -> Option<Duration> {
+        let lo = self.base.as_millis() as u64;
+        let hi = max(lo, (max(previous, self.base).as_millis() as u64).saturating_mul(3));
+        let delay = Duration::from_millis(rand::thread_rng().gen_range(lo..=hi));
+        Some(min(delay, self.cap))
+    }
+}
+
+/// Give up after `max_attempts` connection attempts fail in a row,
+/// otherwise wait as `policy` does.
+///
+/// When the client gives up, outstanding and later requests return
+/// `Error::ConnectAttemptsExhausted`.
+#[derive(Debug)]
+pub struct MaxAttempts<P> {
+    policy: P,
+    max_attempts: u32,
+}
+
+impl<P: ReconnectPolicy> MaxAttempts<P> {
+    /// Construct a new instance.
+    pub fn new(policy: P, max_attempts: u32) -> MaxAttempts<P> {
+        MaxAttempts { policy, max_attempts }
+    }
+}
+
+impl<P: ReconnectPolicy> ReconnectPolicy for MaxAttempts<P> {
+    fn delay(&self, attempt: u32, previous: Duration) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        self.policy.delay(attempt, previous)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delays<P: ReconnectPolicy>(p: &P, n: u32) -> Vec<Option<Duration>> {
+        let mut prev = Duration::from_secs(0);
+        (1..=n).map(|attempt| {
+            let d = p.delay(attempt, prev);
+            prev = d.unwrap_or_default();
+            d
+        }).collect()
+    }
+
+    fn secs(s: &[u64]) -> Vec<Option<Duration>> {
+        s.iter().map(|s| Some(Duration::from_secs(*s))).collect()
+    }
+
+    #[test]
+    fn fixed() {
+        let p = FixedDelay::new(Duration::from_secs(5));
+        assert_eq!(delays(&p, 3), secs(&[5, 5, 5]));
+    }
+
+    #[test]
+    fn exponential() {
+        let p = ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(delays(&p, 6), secs(&[1, 2, 4, 8, 10, 10]));
+        assert_eq!(p.delay(100, Duration::from_secs(10)), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn decorrelated_jitter() {
+        let base = Duration::from_millis(100);
+        let cap = Duration::from_secs(2);
+        let p = DecorrelatedJitter::new(base, cap);
+        let mut prev = Duration::from_secs(0);
+        for attempt in 1..100 {
+            let d = p.delay(attempt, prev).unwrap();
+            assert!(d >= base && d <= cap, "delay {:?}", d);
+            assert!(d <= max(prev, base) * 3, "delay {:?} after {:?}", d, prev);
+            prev = d;
+        }
+    }
+
+    #[test]
+    fn max_attempts() {
+        let p = MaxAttempts::new(FixedDelay::new(Duration::from_secs(1)), 3);
+        assert_eq!(delays(&p, 4), vec![Some(Duration::from_secs(1)),
+                                       Some(Duration::from_secs(1)),
+                                       None,
+                                       None]);
+    }
+}
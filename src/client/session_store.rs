@@ -0,0 +1,576 @@
+use crate::{
+    client::{
+        ConnectionEvent,
+        Publish,
+        QoS,
+    },
+    Error,
+    Result,
+};
+use log::{debug, error};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::{broadcast, Notify},
+    time::{Duration, Instant, sleep, timeout_at},
+};
+
+/// Stores QoS 1 and 2 publishes until the broker acknowledges them,
+/// see `ClientBuilder::set_session_store`.
+///
+/// The client treats the store as a queue: it pushes publishes to the
+/// back and sends them from the front in order, removing each once the
+/// broker has acknowledged it.
+pub trait SessionStore: Debug + Send + Sync {
+    /// Store a publish at the back of the queue, returning its ID.
+    fn push(&self, p: &Publish) -> Result<u64>;
+
+    /// Returns the publish at the front of the queue and its ID,
+    /// without removing it.
+    fn front(&self) -> Result<Option<(u64, Publish)>>;
+
+    /// Remove a publish. Does nothing if there is no publish with
+    /// this ID.
+    fn remove(&self, id: u64) -> Result<()>;
+
+    /// Returns the number of stored publishes.
+    fn len(&self) -> Result<usize>;
+
+    /// Returns true if there are no stored publishes.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// What to do with a publish when the session store is full, see
+/// `ClientBuilder::set_session_store_overflow`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum OverflowPolicy {
+    /// Remove the oldest stored publish to make room.
+    DropOldest,
+
+    /// Discard the new publish, and return `Error::SessionStoreFull`.
+    DropNewest,
+
+    /// Wait for room, up to the operation timeout.
+    #[default]
+    Block,
+}
+
+/// A `SessionStore` that keeps publishes in memory.
+///
+/// Publishes survive reconnects, but not process restarts.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    inner: Mutex<MemoryStoreInner>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStoreInner {
+    queue: VecDeque<(u64, Publish)>,
+    next_id: u64,
+}
+
+impl MemoryStore {
+    /// Construct a new, empty instance.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn push(&self, p: &Publish) -> Result<u64> {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.queue.push_back((id, p.clone()));
+        Ok(id)
+    }
+
+    fn front(&self) -> Result<Option<(u64, Publish)>> {
+        Ok(self.inner.lock().expect("not poisoned").queue.front().cloned())
+    }
+
+    fn remove(&self, id: u64) -> Result<()> {
+        self.inner.lock().expect("not poisoned").queue.retain(|(i, _)| *i != id);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.inner.lock().expect("not poisoned").queue.len())
+    }
+}
+
+/// A `SessionStore` that keeps publishes in an append-only log file,
+/// so they survive process restarts.
+///
+/// Each push and remove is synced to disk before it returns. The log
+/// is rewritten without the removed publishes when it is opened, and
+/// as it grows. MQTT 5 properties are not stored, so publishes read
+/// back from the file have none.
+pub struct FileStore {
+    path: PathBuf,
+    inner: Mutex<FileStoreInner>,
+}
+
+struct FileStoreInner {
+    file: File,
+    queue: BTreeMap<u64, Publish>,
+    next_id: u64,
+
+    /// The number of removes in the log since it was last rewritten.
+    removes: usize,
+}
+
+const RECORD_PUSH: u8 = b'P';
+const RECORD_REMOVE: u8 = b'R';
+
+/// Rewrite the log after this many removes, if most of it is removes.
+const COMPACT_REMOVES: usize = 1000;
+
+/// How long to wait before sending a stored publish again after an
+/// error other than a reason code.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+impl FileStore {
+    /// Open the log file at `path`, creating it if it does not exist,
+    /// and load any publishes stored by a previous process.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStore> {
+        let path = path.as_ref().to_owned();
+        let mut queue = BTreeMap::new();
+        let mut next_id = 0;
+        if path.exists() {
+            let mut data = vec![];
+            File::open(&path)?.read_to_end(&mut data)?;
+            let mut r = &data[..];
+            // A record cut short by a crash ends the log.
+            while let Some((kind, id, p)) = read_record(&mut r) {
+                match (kind, p) {
+                    (RECORD_PUSH, Some(p)) => { queue.insert(id, p); },
+                    _ => { queue.remove(&id); },
+                }
+                next_id = next_id.max(id + 1);
+            }
+        }
+        let file = write_log(&path, &queue)?;
+        Ok(FileStore {
+            path,
+            inner: Mutex::new(FileStoreInner { file, queue, next_id, removes: 0 }),
+        })
+    }
+
+    /// Returns the path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Debug for FileStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileStore")
+         .field("path", &self.path)
+         .finish()
+    }
+}
+
+impl SessionStore for FileStore {
+    fn push(&self, p: &Publish) -> Result<u64> {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        let id = inner.next_id;
+        let mut buf = vec![];
+        push_record(&mut buf, id, p)?;
+        inner.append(&self.path, &buf)?;
+        inner.next_id += 1;
+        inner.queue.insert(id, p.clone());
+        Ok(id)
+    }
+
+    fn front(&self) -> Result<Option<(u64, Publish)>> {
+        let inner = self.inner.lock().expect("not poisoned");
+        Ok(inner.queue.iter().next().map(|(id, p)| (*id, p.clone())))
+    }
+
+    fn remove(&self, id: u64) -> Result<()> {
+        let mut inner = self.inner.lock().expect("not poisoned");
+        let p = match inner.queue.remove(&id) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        if inner.removes + 1 >= COMPACT_REMOVES && inner.removes + 1 > inner.queue.len() {
+            match write_log(&self.path, &inner.queue) {
+                Ok(file) => {
+                    inner.file = file;
+                    inner.removes = 0;
+                    return Ok(());
+                },
+                Err(e) => {
+                    inner.queue.insert(id, p);
+                    return Err(e);
+                },
+            }
+        }
+        let mut buf = vec![RECORD_REMOVE];
+        buf.extend_from_slice(&id.to_be_bytes());
+        if let Err(e) = inner.append(&self.path, &buf) {
+            inner.queue.insert(id, p);
+            return Err(e);
+        }
+        inner.removes += 1;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.inner.lock().expect("not poisoned").queue.len())
+    }
+}
+
+impl FileStoreInner {
+    /// Append a record to the log and sync it to disk. If that fails,
+    /// cut the log back to where it was, so a partial record can't
+    /// hide the records written after it.
+    fn append(&mut self, path: &Path, record: &[u8]) -> Result<()> {
+        let len = self.file.metadata()?.len();
+        let res = self.file.write_all(record).and_then(|()| self.file.sync_data());
+        if let Err(e) = res {
+            if let Err(e) = self.file.set_len(len) {
+                error!("Error truncating the session store log: {}", e);
+                self.file = write_log(path, &self.queue)?;
+            }
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+/// Write a log holding just `queue`, replacing the file at `path`, and
+/// return the new file open for appending.
+fn write_log(path: &Path, queue: &BTreeMap<u64, Publish>) -> Result<File> {
+    let mut buf = vec![];
+    for (id, p) in queue.iter() {
+        push_record(&mut buf, *id, p)?;
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let mut f = File::create(&tmp)?;
+    f.write_all(&buf)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// Encode a push record: kind, ID, QoS, retain, topic and payload.
+fn push_record(buf: &mut Vec<u8>, id: u64, p: &Publish) -> Result<()> {
+    let topic_len = u16::try_from(p.topic().len())
+        .map_err(|_| Error::from("Topic too long to store"))?;
+    let payload_len = u32::try_from(p.payload().len())
+        .map_err(|_| Error::from("Payload too long to store"))?;
+    buf.push(RECORD_PUSH);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.push(match p.qos() {
+        QoS::AtMostOnce => 0,
+        QoS::AtLeastOnce => 1,
+        QoS::ExactlyOnce => 2,
+    });
+    buf.push(p.retain() as u8);
+    buf.extend_from_slice(&topic_len.to_be_bytes());
+    buf.extend_from_slice(p.topic().as_bytes());
+    buf.extend_from_slice(&payload_len.to_be_bytes());
+    buf.extend_from_slice(p.payload());
+    Ok(())
+}
+
+/// Decode the next record, returning its kind, ID and for a push the
+/// publish. Returns `None` at the end of the log or if the record is
+/// incomplete or corrupt.
+fn read_record(r: &mut &[u8]) -> Option<(u8, u64, Option<Publish>)> {
+    let kind = take(r, 1)?[0];
+    let id = u64::from_be_bytes(<[u8; 8]>::try_from(take(r, 8)?).ok()?);
+    if kind == RECORD_REMOVE {
+        return Some((kind, id, None));
+    }
+    if kind != RECORD_PUSH {
+        return None;
+    }
+    let qos = match take(r, 1)?[0] {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => return None,
+    };
+    let retain = take(r, 1)?[0] != 0;
+    let topic_len = u16::from_be_bytes(<[u8; 2]>::try_from(take(r, 2)?).ok()?);
+    let topic = String::from_utf8(take(r, topic_len as usize)?.to_vec()).ok()?;
+    let payload_len = u32::from_be_bytes(<[u8; 4]>::try_from(take(r, 4)?).ok()?);
+    let payload = take(r, payload_len as usize)?.to_vec();
+    let mut p = Publish::new(topic, payload);
+    p.set_qos(qos);
+    p.set_retain(retain);
+    Some((kind, id, Some(p)))
+}
+
+fn take<'a>(r: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if r.len() < n {
+        return None;
+    }
+    let (head, tail) = r.split_at(n);
+    *r = tail;
+    Some(head)
+}
+
+/// The session store with the client's limits. Shared by `Client`,
+/// `ClientHandle` and the task that sends stored publishes.
+#[derive(Debug)]
+pub(crate) struct OutboundQueue {
+    store: Arc<dyn SessionStore>,
+    max_len: usize,
+    overflow: OverflowPolicy,
+
+    /// Held from checking the store has room until pushing, so
+    /// concurrent publishers can't overfill it.
+    push_lock: Mutex<()>,
+
+    /// Notified when a publish is pushed.
+    pushed: Notify,
+
+    /// Notified when a publish is removed.
+    removed: Notify,
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(
+        store: Arc<dyn SessionStore>,
+        max_len: usize,
+        overflow: OverflowPolicy,
+    ) -> OutboundQueue {
+        OutboundQueue {
+            store,
+            max_len,
+            overflow,
+            push_lock: Mutex::new(()),
+            pushed: Notify::new(),
+            removed: Notify::new(),
+        }
+    }
+
+    /// Store a publish to send later, applying the overflow policy if
+    /// the store is full.
+    pub(crate) async fn push(&self, p: &Publish, wait: Duration) -> Result<()> {
+        let deadline = Instant::now() + wait;
+        while !self.try_push(p)? {
+            if timeout_at(deadline, self.removed.notified()).await.is_err() {
+                return Err(Error::Timeout {
+                    operation: "waiting for room in the session store",
+                    after: wait,
+                });
+            }
+        }
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    /// Store a publish if there is room, or the overflow policy makes
+    /// room.
+    ///
+    /// Returns `Ok(false)` if the store is full and the policy is to
+    /// block.
+    fn try_push(&self, p: &Publish) -> Result<bool> {
+        let _guard = self.push_lock.lock().expect("push_lock");
+        while self.store.len()? >= self.max_len {
+            match self.overflow {
+                OverflowPolicy::DropNewest => {
+                    debug!("Session store full, dropping publish to '{}'", p.topic());
+                    return Err(Error::SessionStoreFull);
+                },
+                OverflowPolicy::DropOldest => {
+                    if let Some((id, old)) = self.store.front()? {
+                        debug!("Session store full, dropping publish to '{}'", old.topic());
+                        self.store.remove(id)?;
+                    }
+                },
+                OverflowPolicy::Block => return Ok(false),
+            }
+        }
+        self.store.push(p)?;
+        Ok(true)
+    }
+
+    pub(crate) fn front(&self) -> Result<Option<(u64, Publish)>> {
+        self.store.front()
+    }
+
+    pub(crate) fn remove(&self, id: u64) -> Result<()> {
+        self.store.remove(id)?;
+        self.removed.notify_one();
+        Ok(())
+    }
+
+    /// Wait until a publish is pushed or the connection changes, or
+    /// with `retry` until it is time to retry after an error.
+    /// Returns false when there will be no more connection events.
+    pub(crate) async fn wait(
+        &self,
+        events: &mut broadcast::Receiver<ConnectionEvent>,
+        retry: bool,
+    ) -> bool {
+        let retry_fut = async {
+            match retry {
+                true => sleep(RETRY_DELAY).await,
+                false => futures_util::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = self.pushed.notified() => true,
+            ev = events.recv() => !matches!(ev, Err(broadcast::error::RecvError::Closed)),
+            _ = retry_fut => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn publish(topic: &str, qos: QoS) -> Publish {
+        let mut p = Publish::new(topic.to_owned(), topic.as_bytes().to_vec());
+        p.set_qos(qos);
+        p
+    }
+
+    fn topics(s: &dyn SessionStore) -> Vec<String> {
+        let mut topics = vec![];
+        while let Some((id, p)) = s.front().unwrap() {
+            topics.push(p.topic().to_owned());
+            s.remove(id).unwrap();
+        }
+        topics
+    }
+
+    #[test]
+    fn memory_store_order() {
+        let s = MemoryStore::new();
+        s.push(&publish("a", QoS::AtLeastOnce)).unwrap();
+        let b = s.push(&publish("b", QoS::AtLeastOnce)).unwrap();
+        s.push(&publish("c", QoS::ExactlyOnce)).unwrap();
+        s.remove(b).unwrap();
+        assert_eq!(s.len().unwrap(), 2);
+        assert_eq!(topics(&s), vec!["a", "c"]);
+        assert!(s.is_empty().unwrap());
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let wait = Duration::from_millis(10);
+            let cases = [
+                (OverflowPolicy::DropOldest, vec!["b", "c"]),
+                (OverflowPolicy::DropNewest, vec!["a", "b"]),
+                (OverflowPolicy::Block, vec!["a", "b"]),
+            ];
+            for (policy, expected) in cases.iter() {
+                let store = Arc::new(MemoryStore::new());
+                let q = OutboundQueue::new(store.clone(), 2, *policy);
+                q.push(&publish("a", QoS::AtLeastOnce), wait).await.unwrap();
+                q.push(&publish("b", QoS::AtLeastOnce), wait).await.unwrap();
+                let res = q.push(&publish("c", QoS::AtLeastOnce), wait).await;
+                match policy {
+                    OverflowPolicy::DropOldest => assert!(res.is_ok()),
+                    OverflowPolicy::DropNewest =>
+                        assert!(matches!(res, Err(Error::SessionStoreFull))),
+                    OverflowPolicy::Block => assert!(matches!(res, Err(Error::Timeout { .. }))),
+                }
+                assert_eq!(&topics(&*store), expected);
+            }
+        })
+    }
+
+    /// A store that is slow to push, so concurrent pushes overlap.
+    #[derive(Debug)]
+    struct SlowStore(MemoryStore);
+
+    impl SessionStore for SlowStore {
+        fn push(&self, p: &Publish) -> Result<u64> {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            self.0.push(p)
+        }
+
+        fn front(&self) -> Result<Option<(u64, Publish)>> {
+            self.0.front()
+        }
+
+        fn remove(&self, id: u64) -> Result<()> {
+            self.0.remove(id)
+        }
+
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+    }
+
+    #[test]
+    fn concurrent_pushes_do_not_overfill() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let store = Arc::new(SlowStore(MemoryStore::new()));
+            let q = Arc::new(OutboundQueue::new(store.clone(), 1, OverflowPolicy::DropNewest));
+            let pushes = ["a", "b"].iter().map(|topic| {
+                let q = q.clone();
+                tokio::spawn(async move {
+                    q.push(&publish(topic, QoS::AtLeastOnce), Duration::from_secs(1)).await
+                })
+            }).collect::<Vec<_>>();
+            let mut full = 0;
+            for push in pushes {
+                if let Err(Error::SessionStoreFull) = push.await.unwrap() {
+                    full += 1;
+                }
+            }
+            assert_eq!(full, 1);
+            assert_eq!(store.len().unwrap(), 1);
+        })
+    }
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let path = std::env::temp_dir()
+            .join(format!("mqtt-async-client-test-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let s = FileStore::open(&path).unwrap();
+            s.push(&publish("a", QoS::AtLeastOnce)).unwrap();
+            let b = s.push(&publish("b", QoS::AtLeastOnce)).unwrap();
+            let mut c = publish("c", QoS::ExactlyOnce);
+            c.set_retain(true);
+            s.push(&c).unwrap();
+            s.remove(b).unwrap();
+        }
+        // Simulate a crash part way through writing a record.
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(&[RECORD_PUSH, 0, 0]).unwrap();
+        drop(f);
+
+        let s = FileStore::open(&path).unwrap();
+        let (_, a) = s.front().unwrap().unwrap();
+        assert_eq!((a.topic(), a.payload(), a.qos()), ("a", &b"a"[..], QoS::AtLeastOnce));
+        let d = s.push(&publish("d", QoS::AtLeastOnce)).unwrap();
+        assert!(d > 2);
+        let s = FileStore::open(&path).unwrap();
+        assert_eq!(topics(&s), vec!["a", "c", "d"]);
+        let s = FileStore::open(&path).unwrap();
+        assert!(s.is_empty().unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    client::{
+        ClientHandle,
+        ReadResult,
+        SubscribeResult,
+        Unsubscribe,
+        UnsubscribeTopic,
+    },
+    util::TokioRuntime,
+};
+use futures_core::Stream;
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// A stream of the messages for one subscription, returned by
+/// `Client::subscribe_stream` and `ClientHandle::subscribe_stream`.
+///
+/// Yields each received message whose topic matches one of the
+/// subscription's topic filters. Dropping the `Subscription`
+/// unsubscribes from the filters that no other `Subscription` uses.
+///
+/// The stream ends when the client disconnects. See
+/// `ClientHandle::subscribe_stream` for what happens to messages when
+/// the stream is not read fast enough.
+pub struct Subscription {
+    result: SubscribeResult,
+    rx: mpsc::Receiver<ReadResult>,
+    route_id: u64,
+    handle: ClientHandle,
+}
+
+impl Subscription {
+    pub(crate) fn new(
+        result: SubscribeResult,
+        rx: mpsc::Receiver<ReadResult>,
+        route_id: u64,
+        handle: ClientHandle,
+    ) -> Subscription {
+        Subscription { result, rx, route_id, handle }
+    }
+
+    /// Returns the result of the subscribe operation.
+    pub fn result(&self) -> &SubscribeResult {
+        &self.result
+    }
+}
+
+impl std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscription")
+         .field("result", &self.result)
+         .field("route_id", &self.route_id)
+         .finish()
+    }
+}
+
+impl Stream for Subscription {
+    type Item = ReadResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ReadResult>> {
+        let this = Pin::get_mut(self);
+        this.rx.poll_recv(cx).map(|rr| rr.map(|mut rr| {
+            this.handle.attach_ack(&mut rr);
+            rr
+        }))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let unused = match self.handle.router().lock().expect("router lock")
+                                .remove(self.route_id) {
+            Some(unused) if !unused.is_empty() => unused,
+            // Still in use, or already closed by the IO task.
+            _ => return,
+        };
+        let runtime = self.handle.runtime();
+        if let TokioRuntime::Default = runtime {
+            if tokio::runtime::Handle::try_current().is_err() {
+                debug!("Subscription dropped outside a tokio runtime, not unsubscribing");
+                return;
+            }
+        }
+        let handle = self.handle.clone();
+        runtime.spawn(async move {
+            let u = Unsubscribe::new(unused.into_iter().map(UnsubscribeTopic::new).collect());
+            if let Err(e) = handle.unsubscribe(u).await {
+                debug!("Error unsubscribing dropped Subscription: {}", e);
+            }
+        });
+    }
+}
+
+/// Routes received messages to `Subscription` streams. Shared by
+/// `ClientHandle` and the IO task.
+#[derive(Debug, Default)]
+pub(crate) struct Router {
+    next_id: u64,
+    routes: BTreeMap<u64, Route>,
+}
+
+#[derive(Debug)]
+struct Route {
+    filters: Vec<String>,
+    tx: mpsc::Sender<ReadResult>,
+}
+
+impl Router {
+    /// Add a route, returning its ID.
+    pub(crate) fn add(&mut self, filters: Vec<String>, tx: mpsc::Sender<ReadResult>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.routes.insert(id, Route { filters, tx });
+        id
+    }
+
+    /// Remove a route. Returns its filters that no other route uses,
+    /// or `None` if there was no such route.
+    pub(crate) fn remove(&mut self, id: u64) -> Option<Vec<String>> {
+        let route = self.routes.remove(&id)?;
+        Some(route.filters.into_iter()
+             .filter(|f| !self.routes.values().any(|r| r.filters.contains(f)))
+             .collect())
+    }
+
+    /// Returns the first matching filter and the sender for every
+    /// route with a filter matching `topic`.
+    pub(crate) fn matches(&self, topic: &str) -> Vec<(String, mpsc::Sender<ReadResult>)> {
+        self.routes.values()
+            .filter_map(|r| {
+                let f = r.filters.iter().find(|f| topic_matches(f, topic))?;
+                Some((f.clone(), r.tx.clone()))
+            })
+            .collect()
+    }
+
+    /// Remove all routes, which ends their streams.
+    pub(crate) fn close(&mut self) {
+        self.routes.clear();
+    }
+}
+
+/// Returns whether `topic` matches the topic filter `filter`, which
+/// may contain `+` and `#` wildcards. See
+/// [MQTT 3.1.1 specification section 4.7](http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106)
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Shared subscriptions, "$share/{group}/{filter}".
+    let filter = match filter.strip_prefix("$share/") {
+        Some(rest) => match rest.find('/') {
+            Some(i) => &rest[(i + 1)..],
+            None => return false,
+        },
+        None => filter,
+    };
+    // Wildcards at the start don't match topics like "$SYS/...".
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(f), Some(t)) if f == t => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a/b/c", false),
+            ("a/+", "a/b", true),
+            ("a/+", "a/", true),
+            ("a/+", "a/b/c", false),
+            ("a/+/c", "a/b/c", true),
+            ("+", "a", true),
+            ("+", "/a", false),
+            ("+/+", "/a", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "b/c", false),
+            ("#", "a/b", true),
+            ("#", "$SYS/a", false),
+            ("+/a", "$SYS/a", false),
+            ("$SYS/#", "$SYS/a", true),
+            ("$share/g/a/+", "a/b", true),
+            ("$share/g", "g", false),
+        ];
+        for (filter, topic, expected) in cases.iter() {
+            assert_eq!(topic_matches(filter, topic), *expected, "{} {}", filter, topic);
+        }
+    }
+
+    #[test]
+    fn router_reference_counts() {
+        let mut r = Router::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let a = r.add(vec!["a".to_owned(), "b/+".to_owned()], tx.clone());
+        let b = r.add(vec!["b/+".to_owned()], tx);
+        assert_eq!(r.matches("b/1").len(), 2);
+        assert_eq!(r.matches("a").len(), 1);
+        assert_eq!(r.remove(a), Some(vec!["a".to_owned()]));
+        assert_eq!(r.remove(b), Some(vec!["b/+".to_owned()]));
+        assert_eq!(r.remove(b), None);
+    }
+}
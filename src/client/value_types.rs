@@ -1,9 +1,21 @@
-use crate::Result;
+use crate::{
+    client::ClientHandle,
+    Error,
+    Result,
+};
 use mqttrs::{
     QoS,
+    QosPid,
     SubscribeReturnCodes,
     SubscribeTopic,
 };
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
 use tokio::time::Duration;
 
 /// Arguments for a publish operation.
@@ -13,6 +25,7 @@ pub struct Publish {
     payload: Vec<u8>,
     qos: QoS,
     retain: bool,
+    properties: Properties,
 }
 
 impl Publish {
@@ -23,6 +36,7 @@ impl Publish {
             payload,
             qos: QoS::AtMostOnce,
             retain: false,
+            properties: Properties::default(),
         }
     }
 
@@ -63,6 +77,126 @@ impl Publish {
     pub fn retain(&self) -> bool {
         self.retain
     }
+
+    /// Returns the MQTT 5 properties configured.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Set MQTT 5 properties, e.g. user properties, content type,
+    /// response topic, correlation data, message expiry interval or
+    /// topic alias.
+    ///
+    /// These are only sent when the client is configured with
+    /// `ProtocolVersion::V5`.
+    ///
+    /// The default is no properties.
+    pub fn set_properties(&mut self, properties: Properties) -> &mut Self {
+        self.properties = properties;
+        self
+    }
+}
+
+/// A message for the broker to publish if the client disconnects
+/// unexpectedly, configured with `ClientBuilder::set_last_will`.
+///
+/// See MQTT 3.1.1 section 3.1.2.5 <http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718031>
+#[derive(Clone, Debug)]
+pub struct LastWill {
+    topic: String,
+    payload: Vec<u8>,
+    qos: QoS,
+    retain: bool,
+    properties: Properties,
+}
+
+impl LastWill {
+    /// Construct a new instance.
+    pub fn new(topic: String, payload: Vec<u8>) -> LastWill {
+        LastWill {
+            topic,
+            payload,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Properties::default(),
+        }
+    }
+
+    /// Returns the topic name of this instance.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Returns the payload data of this instance.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Returns the QoS level configured.
+    pub fn qos(&self) -> QoS {
+        self.qos
+    }
+
+    /// Set MQTT quality of service.
+    ///
+    /// The default is QoS::AtMostOnce.
+    pub fn set_qos(&mut self, qos: QoS) -> &mut Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Returns the retain flag value configured.
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+
+    /// Set value of the retain flag.
+    ///
+    /// The default is false.
+    pub fn set_retain(&mut self, retain: bool) -> &mut Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Returns the MQTT 5 will properties configured.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Set MQTT 5 will properties: payload format indicator, message
+    /// expiry interval, content type, response topic, correlation
+    /// data, will delay interval or user properties.
+    ///
+    /// These are only sent when the client is configured with
+    /// `ProtocolVersion::V5`.
+    ///
+    /// The default is no properties.
+    pub fn set_properties(&mut self, properties: Properties) -> &mut Self {
+        self.properties = properties;
+        self
+    }
+
+    /// Returns an error if this is not a valid last will message.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.topic.is_empty() {
+            return Err("Last will topic must not be empty".into());
+        }
+        if self.topic.contains(['+', '#']) {
+            return Err(format!("Last will topic must not contain wildcards: '{}'",
+                               self.topic).into());
+        }
+        if self.payload.len() > u16::MAX as usize {
+            return Err(format!("Last will payload is too long: {} bytes",
+                               self.payload.len()).into());
+        }
+        if self.properties.will_properties() != self.properties {
+            return Err("Last will properties must only be will properties: \
+                        payload_format_indicator, message_expiry_interval, content_type, \
+                        response_topic, correlation_data, will_delay_interval or \
+                        user_properties".into());
+        }
+        Ok(())
+    }
 }
 
 /// Arguments for a subscribe operation.
@@ -89,6 +223,8 @@ impl Subscribe {
 #[derive(Debug)]
 pub struct SubscribeResult {
     pub(crate) return_codes: Vec<SubscribeReturnCodes>,
+    pub(crate) reason_codes: Vec<ReasonCode>,
+    pub(crate) properties: Properties,
 }
 
 impl SubscribeResult {
@@ -97,15 +233,30 @@ impl SubscribeResult {
         &*self.return_codes
     }
 
-    /// Returns an error if any return codes from the operation were `Failure`.
+    /// Returns the reason codes from the operation, one per topic.
+    ///
+    /// With MQTT 3.1.1 these are derived from the return codes, with
+    /// failures reported as `ReasonCode::UnspecifiedError`.
+    pub fn reason_codes(&self) -> &[ReasonCode] {
+        &self.reason_codes
+    }
+
+    /// Returns the MQTT 5 properties of the Suback packet, e.g. the
+    /// reason string. These are always empty with MQTT 3.1.1.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Returns `Error::ReasonCode` with the first failure reason code
+    /// if the broker refused any topic.
     pub fn any_failures(&self) -> Result<()> {
-        let any_failed =
-            self.return_codes().iter()
-                .any(|rc| *rc == SubscribeReturnCodes::Failure);
-        if any_failed {
-            return Err(format!("Some subscribes failed: {:#?}", self.return_codes()).into());
+        match self.reason_codes.iter().find(|rc| !rc.is_success()) {
+            Some(rc) => Err(Error::ReasonCode {
+                reason_code: *rc,
+                reason_string: self.properties.reason_string.clone(),
+            }),
+            None => Ok(()),
         }
-        Ok(())
     }
 }
 
@@ -148,9 +299,44 @@ impl UnsubscribeTopic {
 pub struct ReadResult {
     pub(crate) topic: String,
     pub(crate) payload: Vec<u8>,
+    pub(crate) properties: Properties,
+    pub(crate) qospid: QosPid,
+    pub(crate) retain: bool,
+    pub(crate) dup: bool,
+    pub(crate) received_at: SystemTime,
+    pub(crate) subscription_filter: Option<String>,
+    /// The connection the message arrived on. It is only acknowledged
+    /// on that connection.
+    pub(crate) connection_id: u64,
+    /// The number of copies of this message still to acknowledge,
+    /// shared between the copies sent to each matching `Subscription`.
+    pub(crate) unacked_copies: Arc<AtomicUsize>,
+    pub(crate) ack: Option<Ack>,
 }
 
 impl ReadResult {
+    pub(crate) fn new(
+        p: &mqttrs::Publish,
+        properties: &Properties,
+        subscription_filter: Option<String>,
+        received_at: SystemTime,
+        connection_id: u64,
+    ) -> ReadResult {
+        ReadResult {
+            topic: p.topic_name.clone(),
+            payload: p.payload.clone(),
+            properties: properties.clone(),
+            qospid: p.qospid,
+            retain: p.retain,
+            dup: p.dup,
+            received_at,
+            subscription_filter,
+            connection_id,
+            unacked_copies: Arc::new(AtomicUsize::new(1)),
+            ack: None,
+        }
+    }
+
     /// Returns the topic that was published to.
     pub fn topic(&self) -> &str {
         &*self.topic
@@ -160,6 +346,185 @@ impl ReadResult {
     pub fn payload(&self) -> &[u8] {
         &*self.payload
     }
+
+    /// Returns the MQTT 5 properties that were published. These are
+    /// always empty with MQTT 3.1.1.
+    pub fn properties(&self) -> &Properties {
+        &self.properties
+    }
+
+    /// Returns the QoS the message was delivered with.
+    pub fn qos(&self) -> QoS {
+        self.qospid.qos()
+    }
+
+    /// Returns the packet ID, or `None` for `QoS::AtMostOnce`.
+    pub fn pid(&self) -> Option<u16> {
+        self.qospid.pid().map(|pid| pid.get())
+    }
+
+    /// Returns the retain flag. This is true when the broker sent a
+    /// retained message because of a new subscription, and false for
+    /// messages published while subscribed.
+    pub fn retain(&self) -> bool {
+        self.retain
+    }
+
+    /// Returns the DUP flag. This is true when the broker may have
+    /// sent the message before, e.g. a QoS 1 redelivery after a
+    /// reconnect.
+    pub fn dup(&self) -> bool {
+        self.dup
+    }
+
+    /// Returns the local time the message was received.
+    pub fn received_at(&self) -> SystemTime {
+        self.received_at
+    }
+
+    /// Returns the subscription topic filter that the message's
+    /// topic matched, if any.
+    ///
+    /// For a `Subscription` stream this is one of its filters. If
+    /// several filters match, one of them is returned.
+    pub fn subscription_filter(&self) -> Option<&str> {
+        self.subscription_filter.as_deref()
+    }
+
+    /// Takes the token to acknowledge this message, so it can be
+    /// acknowledged later, e.g. from another task.
+    ///
+    /// This returns `None` unless the client was built with
+    /// `ClientBuilder::set_manual_acks`, for `QoS::AtMostOnce`
+    /// messages, and once the token was taken.
+    pub fn take_ack(&mut self) -> Option<Ack> {
+        self.ack.take()
+    }
+
+    /// Acknowledge this message, when the client was built with
+    /// `ClientBuilder::set_manual_acks`. Does nothing if there is no
+    /// token to acknowledge it with, see `take_ack`.
+    pub async fn ack(&mut self) -> Result<()> {
+        match self.ack.take() {
+            Some(ack) => ack.ack().await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A token to acknowledge a received QoS 1 or 2 message, see
+/// `ClientBuilder::set_manual_acks`.
+///
+/// Dropping the token without calling `ack` leaves the message
+/// unacknowledged. Holding a token keeps the client's IO task running,
+/// like a `ClientHandle`.
+#[derive(Debug)]
+pub struct Ack {
+    handle: ClientHandle,
+    qospid: QosPid,
+    connection_id: u64,
+    unacked_copies: Arc<AtomicUsize>,
+}
+
+impl Ack {
+    pub(crate) fn new(handle: ClientHandle, rr: &ReadResult) -> Ack {
+        Ack {
+            handle,
+            qospid: rr.qospid,
+            connection_id: rr.connection_id,
+            unacked_copies: rr.unacked_copies.clone(),
+        }
+    }
+
+    /// Send the Puback, or the Pubrec for `QoS::ExactlyOnce`.
+    ///
+    /// When the message matched several `Subscription` streams, it
+    /// is acknowledged once every stream's copy has been.
+    ///
+    /// Does nothing if the client has reconnected since the message
+    /// arrived, because its packet ID may now belong to another
+    /// message. With a persistent session the broker sends the
+    /// message again instead.
+    pub async fn ack(self) -> Result<()> {
+        if self.unacked_copies.fetch_sub(1, Ordering::AcqRel) > 1 {
+            return Ok(());
+        }
+        self.handle.acknowledge(self.qospid, self.connection_id).await
+    }
+}
+
+/// A change in the state of a client's connection, see `Client::events`.
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// The client is opening a connection.
+    Connecting,
+
+    /// The client connected and the broker accepted the connection.
+    Connected {
+        /// The session present flag from the broker, see
+        /// `Client::session_present`.
+        session_present: bool,
+    },
+
+    /// A connection attempt failed.
+    ConnectFailed {
+        /// Why the attempt failed.
+        error: Arc<Error>,
+    },
+
+    /// The connection was closed.
+    Disconnected {
+        /// Why the connection was closed.
+        reason: DisconnectReason,
+    },
+
+    /// Every endpoint failed to connect, or an established connection
+    /// was lost, so the client will wait and then try again, see
+    /// `ClientBuilder::set_reconnect_policy`.
+    Reconnecting {
+        /// The number of times in a row every endpoint has failed, or
+        /// 0 after losing an established connection.
+        attempt: u32,
+
+        /// How long the client waits before trying again.
+        delay: Duration,
+    },
+
+    /// The client replayed its subscriptions after connecting with a
+    /// clean session, and the broker responded.
+    SubscriptionsReplayed {
+        /// Each topic filter and the reason code the broker returned for it.
+        results: Vec<(String, ReasonCode)>,
+    },
+
+    /// The broker did not answer a keep alive ping in time. The
+    /// client closes the connection.
+    PingTimeout,
+}
+
+/// Why a connection was closed, see `ConnectionEvent::Disconnected`.
+#[derive(Clone, Debug)]
+pub enum DisconnectReason {
+    /// The client disconnected or was dropped.
+    Client,
+
+    /// The broker closed the connection with an MQTT 5 Disconnect packet.
+    Server {
+        /// The reason code.
+        reason_code: ReasonCode,
+
+        /// The reason string property sent with the code, if any.
+        reason_string: Option<String>,
+    },
+
+    /// The broker did not answer a keep alive ping in time.
+    PingTimeout,
+
+    /// The broker broke the MQTT protocol.
+    ProtocolError,
+
+    /// The connection was closed without a Disconnect packet, or failed.
+    ConnectionLost,
 }
 
 /// Represents the keep alive setting for a client.
@@ -218,3 +583,491 @@ impl KeepAlive {
         }
     }
 }
+
+/// A broker to connect to, see `ClientBuilder::set_endpoints`.
+#[derive(Clone)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+    #[cfg(feature = "tls")]
+    tls_client_config: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Endpoint {
+    /// Construct a new endpoint.
+    pub fn new(host: String, port: u16) -> Endpoint {
+        Endpoint {
+            host,
+            port,
+            #[cfg(feature = "tls")]
+            tls_client_config: None,
+        }
+    }
+
+    /// Returns the host.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the TCP port.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Set the TLS ClientConfig for connections to this endpoint.
+    ///
+    /// Enables TLS for this endpoint. By default endpoints use the
+    /// client's TLS settings, see `ClientBuilder::set_tls_client_config`.
+    #[cfg(feature = "tls")]
+    pub fn set_tls_client_config(&mut self, tls_client_config: rustls::ClientConfig) -> &mut Self {
+        self.tls_client_config = Some(Arc::new(tls_client_config));
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_client_config(&self) -> Option<&Arc<rustls::ClientConfig>> {
+        self.tls_client_config.as_ref()
+    }
+}
+
+impl std::fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut d = f.debug_struct("Endpoint");
+        d.field("host", &self.host)
+         .field("port", &self.port);
+        #[cfg(feature = "tls")]
+        d.field("tls", &self.tls_client_config.is_some());
+        d.finish()
+    }
+}
+
+/// How the client chooses which endpoint to connect to, see
+/// `ClientBuilder::set_endpoint_policy`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum EndpointPolicy {
+    /// Try the endpoints in turn. After a failed connection attempt or
+    /// a lost connection the client moves on to the next endpoint.
+    #[default]
+    RoundRobin,
+
+    /// Prefer the first endpoint. After a failed connection attempt the
+    /// client falls back to the next endpoint, and it stays connected
+    /// to that endpoint until the connection is lost. Each reconnect
+    /// starts again with the first endpoint.
+    Primary,
+}
+
+/// Options to connect over WebSocket, configured with
+/// `ClientBuilder::set_websocket`.
+///
+/// MQTT packets are sent in binary WebSocket messages using the
+/// "mqtt" subprotocol. The connection uses `wss://` when a TLS client
+/// config is set, otherwise `ws://`.
+#[cfg(feature = "websocket")]
+#[derive(Clone)]
+pub struct WebSocketOptions {
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+#[cfg(feature = "websocket")]
+impl WebSocketOptions {
+    /// Construct new options with the path "/mqtt" and no extra headers.
+    pub fn new() -> WebSocketOptions {
+        WebSocketOptions {
+            path: "/mqtt".to_owned(),
+            headers: vec![],
+        }
+    }
+
+    /// Returns the HTTP path requested in the WebSocket handshake.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Set the HTTP path requested in the WebSocket handshake,
+    /// including any query string. Must start with '/'.
+    ///
+    /// The default is "/mqtt".
+    pub fn set_path(&mut self, path: String) -> &mut Self {
+        self.path = path;
+        self
+    }
+
+    /// Returns the extra headers sent in the WebSocket handshake.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Add an extra header to send in the WebSocket handshake, e.g.
+    /// for authentication with a proxy.
+    pub fn add_header(&mut self, name: String, value: String) -> &mut Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if !self.path.starts_with('/') {
+            return Err(format!("WebSocket path must start with '/': '{}'", self.path)
+                       .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Default for WebSocketOptions {
+    fn default() -> WebSocketOptions {
+        WebSocketOptions::new()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl std::fmt::Debug for WebSocketOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Skipping header values, which may hold credentials.
+        f.debug_struct("WebSocketOptions")
+         .field("path", &self.path)
+         .field("header_names", &self.headers.iter().map(|(n, _)| n).collect::<Vec<_>>())
+         .finish()
+    }
+}
+
+/// The version of the MQTT protocol to connect with.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1.
+    #[default]
+    V311,
+
+    /// MQTT 5.0. See <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html>
+    V5,
+}
+
+impl std::str::FromStr for ProtocolVersion {
+    type Err = Error;
+
+    /// Parse "3.1.1" (or "4", its protocol level) or "5".
+    fn from_str(s: &str) -> Result<ProtocolVersion> {
+        match s {
+            "3.1.1" | "4" => Ok(ProtocolVersion::V311),
+            "5" => Ok(ProtocolVersion::V5),
+            _ => Err(format!("Unknown MQTT protocol version '{}', expected \"3.1.1\" or \"5\"",
+                             s).into()),
+        }
+    }
+}
+
+/// MQTT 5 properties sent or received with a packet.
+///
+/// Each packet type only permits some of these, see MQTT 5.0 section
+/// 2.2.2.2 <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901029>.
+/// Fields that are `None` or empty are not sent.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Properties {
+    /// Payload Format Indicator: 0 for unspecified bytes, 1 for UTF-8 data.
+    pub payload_format_indicator: Option<u8>,
+
+    /// Message Expiry Interval in seconds.
+    pub message_expiry_interval: Option<u32>,
+
+    /// Content Type, e.g. a MIME type.
+    pub content_type: Option<String>,
+
+    /// Response Topic for a request / response exchange.
+    pub response_topic: Option<String>,
+
+    /// Correlation Data for a request / response exchange.
+    pub correlation_data: Option<Vec<u8>>,
+
+    /// Subscription Identifiers.
+    pub subscription_identifiers: Vec<u32>,
+
+    /// Session Expiry Interval in seconds.
+    pub session_expiry_interval: Option<u32>,
+
+    /// Assigned Client Identifier.
+    pub assigned_client_identifier: Option<String>,
+
+    /// Server Keep Alive in seconds.
+    pub server_keep_alive: Option<u16>,
+
+    /// Authentication Method.
+    pub authentication_method: Option<String>,
+
+    /// Authentication Data.
+    pub authentication_data: Option<Vec<u8>>,
+
+    /// Request Problem Information.
+    pub request_problem_information: Option<u8>,
+
+    /// Will Delay Interval in seconds.
+    pub will_delay_interval: Option<u32>,
+
+    /// Request Response Information.
+    pub request_response_information: Option<u8>,
+
+    /// Response Information.
+    pub response_information: Option<String>,
+
+    /// Server Reference.
+    pub server_reference: Option<String>,
+
+    /// Reason String, a human readable explanation of a reason code.
+    pub reason_string: Option<String>,
+
+    /// Receive Maximum.
+    pub receive_maximum: Option<u16>,
+
+    /// Topic Alias Maximum.
+    pub topic_alias_maximum: Option<u16>,
+
+    /// Topic Alias.
+    pub topic_alias: Option<u16>,
+
+    /// Maximum QoS.
+    pub maximum_qos: Option<u8>,
+
+    /// Retain Available.
+    pub retain_available: Option<u8>,
+
+    /// User Properties as (name, value) pairs, in order.
+    pub user_properties: Vec<(String, String)>,
+
+    /// Maximum Packet Size in bytes.
+    pub maximum_packet_size: Option<u32>,
+
+    /// Wildcard Subscription Available.
+    pub wildcard_subscription_available: Option<u8>,
+
+    /// Subscription Identifier Available.
+    pub subscription_identifier_available: Option<u8>,
+
+    /// Shared Subscription Available.
+    pub shared_subscription_available: Option<u8>,
+}
+
+impl Properties {
+    /// Returns true if no properties are set.
+    pub fn is_empty(&self) -> bool {
+        *self == Properties::default()
+    }
+
+    /// Returns only the properties permitted in a last will.
+    ///
+    /// See MQTT 5.0 section 3.1.3.2 <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901060>
+    pub(crate) fn will_properties(&self) -> Properties {
+        Properties {
+            payload_format_indicator: self.payload_format_indicator,
+            message_expiry_interval: self.message_expiry_interval,
+            content_type: self.content_type.clone(),
+            response_topic: self.response_topic.clone(),
+            correlation_data: self.correlation_data.clone(),
+            will_delay_interval: self.will_delay_interval,
+            user_properties: self.user_properties.clone(),
+            ..Properties::default()
+        }
+    }
+}
+
+/// An MQTT 5 reason code, sent in acknowledgement and Disconnect packets.
+///
+/// Values below 0x80 indicate success, 0x80 and above indicate failure.
+/// See MQTT 5.0 section 2.4 <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901031>
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReasonCode {
+    /// 0x00: Success, Normal disconnection, or Granted QoS 0.
+    Success,
+    /// 0x01
+    GrantedQoS1,
+    /// 0x02
+    GrantedQoS2,
+    /// 0x04
+    DisconnectWithWillMessage,
+    /// 0x10
+    NoMatchingSubscribers,
+    /// 0x11
+    NoSubscriptionExisted,
+    /// 0x18
+    ContinueAuthentication,
+    /// 0x19
+    ReAuthenticate,
+    /// 0x80
+    UnspecifiedError,
+    /// 0x81
+    MalformedPacket,
+    /// 0x82
+    ProtocolError,
+    /// 0x83
+    ImplementationSpecificError,
+    /// 0x84
+    UnsupportedProtocolVersion,
+    /// 0x85
+    ClientIdentifierNotValid,
+    /// 0x86
+    BadUserNameOrPassword,
+    /// 0x87
+    NotAuthorized,
+    /// 0x88
+    ServerUnavailable,
+    /// 0x89
+    ServerBusy,
+    /// 0x8A
+    Banned,
+    /// 0x8B
+    ServerShuttingDown,
+    /// 0x8C
+    BadAuthenticationMethod,
+    /// 0x8D
+    KeepAliveTimeout,
+    /// 0x8E
+    SessionTakenOver,
+    /// 0x8F
+    TopicFilterInvalid,
+    /// 0x90
+    TopicNameInvalid,
+    /// 0x91
+    PacketIdentifierInUse,
+    /// 0x92
+    PacketIdentifierNotFound,
+    /// 0x93
+    ReceiveMaximumExceeded,
+    /// 0x94
+    TopicAliasInvalid,
+    /// 0x95
+    PacketTooLarge,
+    /// 0x96
+    MessageRateTooHigh,
+    /// 0x97
+    QuotaExceeded,
+    /// 0x98
+    AdministrativeAction,
+    /// 0x99
+    PayloadFormatInvalid,
+    /// 0x9A
+    RetainNotSupported,
+    /// 0x9B
+    QoSNotSupported,
+    /// 0x9C
+    UseAnotherServer,
+    /// 0x9D
+    ServerMoved,
+    /// 0x9E
+    SharedSubscriptionsNotSupported,
+    /// 0x9F
+    ConnectionRateExceeded,
+    /// 0xA0
+    MaximumConnectTime,
+    /// 0xA1
+    SubscriptionIdentifiersNotSupported,
+    /// 0xA2
+    WildcardSubscriptionsNotSupported,
+}
+
+impl ReasonCode {
+    /// Returns true if this code indicates success.
+    pub fn is_success(&self) -> bool {
+        self.to_u8() < 0x80
+    }
+
+    /// Returns the numeric value of this code.
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::GrantedQoS1 => 0x01,
+            ReasonCode::GrantedQoS2 => 0x02,
+            ReasonCode::DisconnectWithWillMessage => 0x04,
+            ReasonCode::NoMatchingSubscribers => 0x10,
+            ReasonCode::NoSubscriptionExisted => 0x11,
+            ReasonCode::ContinueAuthentication => 0x18,
+            ReasonCode::ReAuthenticate => 0x19,
+            ReasonCode::UnspecifiedError => 0x80,
+            ReasonCode::MalformedPacket => 0x81,
+            ReasonCode::ProtocolError => 0x82,
+            ReasonCode::ImplementationSpecificError => 0x83,
+            ReasonCode::UnsupportedProtocolVersion => 0x84,
+            ReasonCode::ClientIdentifierNotValid => 0x85,
+            ReasonCode::BadUserNameOrPassword => 0x86,
+            ReasonCode::NotAuthorized => 0x87,
+            ReasonCode::ServerUnavailable => 0x88,
+            ReasonCode::ServerBusy => 0x89,
+            ReasonCode::Banned => 0x8A,
+            ReasonCode::ServerShuttingDown => 0x8B,
+            ReasonCode::BadAuthenticationMethod => 0x8C,
+            ReasonCode::KeepAliveTimeout => 0x8D,
+            ReasonCode::SessionTakenOver => 0x8E,
+            ReasonCode::TopicFilterInvalid => 0x8F,
+            ReasonCode::TopicNameInvalid => 0x90,
+            ReasonCode::PacketIdentifierInUse => 0x91,
+            ReasonCode::PacketIdentifierNotFound => 0x92,
+            ReasonCode::ReceiveMaximumExceeded => 0x93,
+            ReasonCode::TopicAliasInvalid => 0x94,
+            ReasonCode::PacketTooLarge => 0x95,
+            ReasonCode::MessageRateTooHigh => 0x96,
+            ReasonCode::QuotaExceeded => 0x97,
+            ReasonCode::AdministrativeAction => 0x98,
+            ReasonCode::PayloadFormatInvalid => 0x99,
+            ReasonCode::RetainNotSupported => 0x9A,
+            ReasonCode::QoSNotSupported => 0x9B,
+            ReasonCode::UseAnotherServer => 0x9C,
+            ReasonCode::ServerMoved => 0x9D,
+            ReasonCode::SharedSubscriptionsNotSupported => 0x9E,
+            ReasonCode::ConnectionRateExceeded => 0x9F,
+            ReasonCode::MaximumConnectTime => 0xA0,
+            ReasonCode::SubscriptionIdentifiersNotSupported => 0xA1,
+            ReasonCode::WildcardSubscriptionsNotSupported => 0xA2,
+        }
+    }
+
+    /// Returns the code with numeric value `v`, or None if `v` is not
+    /// a valid reason code.
+    pub fn from_u8(v: u8) -> Option<ReasonCode> {
+        Some(match v {
+            0x00 => ReasonCode::Success,
+            0x01 => ReasonCode::GrantedQoS1,
+            0x02 => ReasonCode::GrantedQoS2,
+            0x04 => ReasonCode::DisconnectWithWillMessage,
+            0x10 => ReasonCode::NoMatchingSubscribers,
+            0x11 => ReasonCode::NoSubscriptionExisted,
+            0x18 => ReasonCode::ContinueAuthentication,
+            0x19 => ReasonCode::ReAuthenticate,
+            0x80 => ReasonCode::UnspecifiedError,
+            0x81 => ReasonCode::MalformedPacket,
+            0x82 => ReasonCode::ProtocolError,
+            0x83 => ReasonCode::ImplementationSpecificError,
+            0x84 => ReasonCode::UnsupportedProtocolVersion,
+            0x85 => ReasonCode::ClientIdentifierNotValid,
+            0x86 => ReasonCode::BadUserNameOrPassword,
+            0x87 => ReasonCode::NotAuthorized,
+            0x88 => ReasonCode::ServerUnavailable,
+            0x89 => ReasonCode::ServerBusy,
+            0x8A => ReasonCode::Banned,
+            0x8B => ReasonCode::ServerShuttingDown,
+            0x8C => ReasonCode::BadAuthenticationMethod,
+            0x8D => ReasonCode::KeepAliveTimeout,
+            0x8E => ReasonCode::SessionTakenOver,
+            0x8F => ReasonCode::TopicFilterInvalid,
+            0x90 => ReasonCode::TopicNameInvalid,
+            0x91 => ReasonCode::PacketIdentifierInUse,
+            0x92 => ReasonCode::PacketIdentifierNotFound,
+            0x93 => ReasonCode::ReceiveMaximumExceeded,
+            0x94 => ReasonCode::TopicAliasInvalid,
+            0x95 => ReasonCode::PacketTooLarge,
+            0x96 => ReasonCode::MessageRateTooHigh,
+            0x97 => ReasonCode::QuotaExceeded,
+            0x98 => ReasonCode::AdministrativeAction,
+            0x99 => ReasonCode::PayloadFormatInvalid,
+            0x9A => ReasonCode::RetainNotSupported,
+            0x9B => ReasonCode::QoSNotSupported,
+            0x9C => ReasonCode::UseAnotherServer,
+            0x9D => ReasonCode::ServerMoved,
+            0x9E => ReasonCode::SharedSubscriptionsNotSupported,
+            0x9F => ReasonCode::ConnectionRateExceeded,
+            0xA0 => ReasonCode::MaximumConnectTime,
+            0xA1 => ReasonCode::SubscriptionIdentifiersNotSupported,
+            0xA2 => ReasonCode::WildcardSubscriptionsNotSupported,
+            _ => return None,
+        })
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    Error,
+    Result,
+    util::FreePidList,
+};
+use mqttrs::Pid;
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::Semaphore,
+    time::{
+        Duration,
+        error::Elapsed,
+        timeout,
+    },
+};
+
+/// The Pid the IO task uses to replay subscriptions after
+/// reconnecting. `WritePids` never allocates it.
+pub(crate) const REPLAY_PID: u16 = u16::MAX;
+
+/// Tracks the Pids (MQTT packet IDs) of requests sent to the broker.
+///
+/// Shared by `Client`, `ClientHandle` and the IO task, so it outlives a
+/// single connection.
+///
+/// A Pid is orphaned when its request fails or the Client stops
+/// waiting for it, e.g. after a timeout. The broker may still respond
+/// to it, so an orphaned Pid stays in use until a late response arrives
+/// or the client connects with a clean session.
+pub(crate) struct WritePids {
+    state: Mutex<State>,
+
+    /// Limits the number of Pids in use to the maximum in flight.
+    in_flight: Semaphore,
+}
+
+struct State {
+    free: FreePidList,
+
+    orphaned: BTreeSet<u16>,
+
+    /// Pids that received a late response before their request was
+    /// orphaned.
+    late: BTreeSet<u16>,
+}
+
+impl WritePids {
+    pub(crate) fn new(max_in_flight: u16) -> WritePids {
+        WritePids {
+            state: Mutex::new(State {
+                free: FreePidList::with_bounds(1, REPLAY_PID - 1),
+                orphaned: BTreeSet::new(),
+                late: BTreeSet::new(),
+            }),
+            in_flight: Semaphore::new(max_in_flight as usize),
+        }
+    }
+
+    /// Allocate a Pid, first waiting in turn up to `wait` until fewer
+    /// than the maximum in flight are in use.
+    ///
+    /// The Pid is orphaned if the returned guard is dropped without
+    /// calling `PidGuard::free`.
+    pub(crate) async fn alloc(self: &Arc<Self>, wait: Duration) -> Result<PidGuard> {
+        let res = timeout(wait, self.in_flight.acquire()).await;
+        if let Err(Elapsed { .. }) = res {
+            return Err(Error::Timeout {
+                operation: "waiting for a free Pid",
+                after: wait,
+            });
+        }
+        // The semaphore is never closed.
+        let permit = res.expect("No timeout").expect("Semaphore open");
+        // The permit is returned when the Pid is freed.
+        permit.forget();
+        match self.state.lock().expect("not poisoned").free.alloc() {
+            Some(pid) => Ok(PidGuard {
+                pids: self.clone(),
+                pid: Pid::try_from(pid).expect("Non-zero Pid"),
+                freed: false,
+            }),
+            None => {
+                self.in_flight.add_permits(1);
+                Err(Error::PidsExhausted)
+            },
+        }
+    }
+
+    /// Record a response to a request the Client stopped waiting for.
+    pub(crate) fn late_response(&self, pid: Pid) {
+        let mut state = self.state.lock().expect("not poisoned");
+        if state.orphaned.remove(&pid.get()) {
+            self.free(&mut state, pid.get());
+        } else {
+            // The request's guard has not been dropped yet.
+            state.late.insert(pid.get());
+        }
+    }
+
+    /// Free every orphaned Pid, because the broker has no session
+    /// state that could refer to them.
+    pub(crate) fn free_orphaned(&self) {
+        let mut state = self.state.lock().expect("not poisoned");
+        for pid in std::mem::take(&mut state.orphaned) {
+            self.free(&mut state, pid);
+        }
+    }
+
+    fn orphan(&self, pid: Pid) {
+        let mut state = self.state.lock().expect("not poisoned");
+        if state.late.remove(&pid.get()) {
+            self.free(&mut state, pid.get());
+        } else {
+            state.orphaned.insert(pid.get());
+        }
+    }
+
+    fn free(&self, state: &mut State, pid: u16) -> bool {
+        if state.free.free(pid) {
+            return false;
+        }
+        self.in_flight.add_permits(1);
+        true
+    }
+}
+
+/// An allocated Pid, which is orphaned if dropped before it is freed.
+pub(crate) struct PidGuard {
+    pids: Arc<WritePids>,
+    pid: Pid,
+    freed: bool,
+}
+
+impl PidGuard {
+    pub(crate) fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Free the Pid once the broker has completed its request.
+    pub(crate) fn free(mut self) -> Result<()> {
+        self.freed = true;
+        let mut state = self.pids.state.lock().expect("not poisoned");
+        match self.pids.free(&mut state, self.pid.get()) {
+            true => Ok(()),
+            false => Err(Error::InvalidState("Pid was already free".to_owned())),
+        }
+    }
+}
+
+impl Drop for PidGuard {
+    fn drop(&mut self) {
+        if !self.freed {
+            self.pids.orphan(self.pid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{
+        Client,
+        Publish,
+        Subscribe,
+        fake_broker::{FakeConnector, fake_broker},
+    };
+    use mqttrs::{Packet, QoS, QosPid, Suback, SubscribeReturnCodes, SubscribeTopic};
+
+    fn available(pids: &WritePids) -> usize {
+        pids.in_flight.available_permits()
+    }
+
+    #[test]
+    fn orphaned_pid_freed_by_late_response() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pids = Arc::new(WritePids::new(2));
+            let wait = Duration::from_millis(10);
+            let a = pids.alloc(wait).await.unwrap();
+            let a_pid = a.pid();
+            let b = pids.alloc(wait).await.unwrap();
+            assert!(pids.alloc(wait).await.is_err());
+
+            drop(a);
+            assert_eq!(available(&pids), 0);
+            pids.late_response(a_pid);
+            assert_eq!(available(&pids), 1);
+
+            // A late response can arrive before the guard is dropped.
+            let b_pid = b.pid();
+            pids.late_response(b_pid);
+            assert_eq!(available(&pids), 1);
+            drop(b);
+            assert_eq!(available(&pids), 2);
+        })
+    }
+
+    #[test]
+    fn free_orphaned() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let pids = Arc::new(WritePids::new(2));
+            let wait = Duration::from_millis(10);
+            drop(pids.alloc(wait).await.unwrap());
+            let b = pids.alloc(wait).await.unwrap();
+            assert_eq!(available(&pids), 0);
+            pids.free_orphaned();
+            assert_eq!(available(&pids), 1);
+            b.free().unwrap();
+            assert_eq!(available(&pids), 2);
+        })
+    }
+
+    fn persistent_client(connector: FakeConnector) -> Client {
+        Client::builder()
+            .set_host("fake".to_owned())
+            .set_connector(connector)
+            .set_connect_retry_delay(Duration::from_millis(10))
+            .set_clean_session(false)
+            .set_client_id(Some("c".to_owned()))
+            .set_max_in_flight(1)
+            .set_publish_timeout(Duration::from_millis(100))
+            .build().unwrap()
+    }
+
+    fn publish_pid(p: Packet) -> Pid {
+        match p {
+            Packet::Publish(mqttrs::Publish { qospid: QosPid::AtLeastOnce(pid), .. }) => pid,
+            p => panic!("Unexpected packet {:?}", p),
+        }
+    }
+
+    #[test]
+    fn timed_out_publish_freed_after_reconnect() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = persistent_client(connector);
+            c.connect().await.unwrap();
+            let mut conn = broker.accept(false).await;
+            let mut p = Publish::new("a".to_owned(), b"x".to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            let h = c.handle().unwrap();
+            let publish = tokio::spawn(async move { h.publish(&p).await });
+            let pid = publish_pid(conn.read().await);
+            assert!(matches!(publish.await.unwrap(), Err(Error::Timeout { .. })));
+
+            // The broker resumes the session, so the orphaned publish is
+            // resent and its Puback frees the only permit.
+            drop(conn);
+            let mut conn = broker.accept(true).await;
+            assert_eq!(publish_pid(conn.read().await), pid);
+            conn.write(&Packet::Puback(pid)).await;
+
+            let mut p = Publish::new("b".to_owned(), b"y".to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            let h = c.handle().unwrap();
+            let publish = tokio::spawn(async move { h.publish(&p).await });
+            let pid = publish_pid(conn.read().await);
+            conn.write(&Packet::Puback(pid)).await;
+            publish.await.unwrap().unwrap();
+        })
+    }
+
+    #[test]
+    fn disconnected_subscribe_freed() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, mut broker) = fake_broker();
+            let mut c = persistent_client(connector);
+            c.connect().await.unwrap();
+            let mut conn = broker.accept(false).await;
+            let s = Subscribe::new(vec![SubscribeTopic {
+                topic_path: "a".to_owned(),
+                qos: QoS::AtLeastOnce,
+            }]);
+            let h = c.handle().unwrap();
+            let subscribe = tokio::spawn(async move { h.subscribe(s).await });
+            assert!(matches!(conn.read().await, Packet::Subscribe(_)));
+            drop(conn);
+            let mut conn = broker.accept(true).await;
+            assert!(matches!(subscribe.await.unwrap(), Err(Error::Disconnected)));
+
+            let mut p = Publish::new("b".to_owned(), b"y".to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            let h = c.handle().unwrap();
+            let publish = tokio::spawn(async move { h.publish(&p).await });
+            loop {
+                match conn.read().await {
+                    // Answer the replayed subscription.
+                    Packet::Subscribe(s) => conn.write(&Packet::Suback(Suback {
+                        pid: s.pid,
+                        return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)],
+                    })).await,
+                    p => {
+                        conn.write(&Packet::Puback(publish_pid(p))).await;
+                        break;
+                    },
+                }
+            }
+            publish.await.unwrap().unwrap();
+        })
+    }
+}
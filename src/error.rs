@@ -1,6 +1,11 @@
+use crate::client::{
+    ConnectReturnCode,
+    ReasonCode,
+};
 use std::{
     convert::From,
     fmt::{Debug, Display, Formatter, self},
+    time::Duration,
 };
 
 /// Fallible result values returned by the library.
@@ -18,6 +23,81 @@ pub enum Error {
     /// An error represented as a String.
     String(String),
 
+    /// The server responded with an MQTT 5 reason code indicating failure.
+    ReasonCode {
+        /// The reason code.
+        reason_code: ReasonCode,
+
+        /// The reason string property sent with the code, if any.
+        reason_string: Option<String>,
+    },
+
+    /// The server closed the connection with an MQTT 5 Disconnect packet.
+    ServerDisconnected {
+        /// The reason code.
+        reason_code: ReasonCode,
+
+        /// The reason string property sent with the code, if any.
+        reason_string: Option<String>,
+    },
+
+    /// A TLS certificate could not be loaded or used.
+    Certificate(String),
+
+    /// A TLS private key could not be loaded or used.
+    PrivateKey(String),
+
+    /// The client gave up connecting, as allowed by its `ReconnectPolicy`.
+    ConnectAttemptsExhausted {
+        /// The number of connection attempts that failed in a row.
+        attempts: u32,
+
+        /// The error from the last connection attempt.
+        last_error: String,
+    },
+
+    /// An operation did not complete in time.
+    Timeout {
+        /// What timed out, e.g. "waiting for Puback".
+        operation: &'static str,
+
+        /// How long the client waited.
+        after: Duration,
+    },
+
+    /// The server refused the connection with an MQTT 3.1.1 return code.
+    ConnectionRefused(ConnectReturnCode),
+
+    /// A packet broke the MQTT protocol, e.g. the server sent a
+    /// malformed packet or one the client did not expect.
+    ProtocolViolation {
+        /// What the protocol allows.
+        expected: String,
+
+        /// What was sent instead.
+        got: String,
+    },
+
+    /// Every packet ID is in use, see `ClientBuilder::set_max_in_flight`.
+    PidsExhausted,
+
+    /// The session store is full and its overflow policy discards new
+    /// publishes, see `ClientBuilder::set_session_store_overflow`.
+    SessionStoreFull,
+
+    /// The client can't do that in its current state, e.g. publish
+    /// before calling `connect`.
+    InvalidState(String),
+
+    /// The TLS session could not be established.
+    Tls(Box<dyn std::error::Error + Send + Sync>),
+
+    /// An IO error, e.g. from the network connection.
+    Io(std::io::Error),
+
+    /// The proxy could not open a tunnel to the broker.
+    Proxy(String),
+
     #[doc(hidden)]
     _NonExhaustive
 }
@@ -35,15 +115,46 @@ impl Display for Error {
             Error::Disconnected => write!(f, "Disconnected"),
             Error::StdError(e) => write!(f, "{}", e),
             Error::String(s) => write!(f, "{}", s),
+            Error::ReasonCode { reason_code, reason_string } =>
+                write!(f, "Server returned reason code {:?}{}", reason_code,
+                       fmt_reason_string(reason_string)),
+            Error::ServerDisconnected { reason_code, reason_string } =>
+                write!(f, "Server disconnected with reason code {:?}{}", reason_code,
+                       fmt_reason_string(reason_string)),
+            Error::Certificate(s) => write!(f, "Certificate error: {}", s),
+            Error::PrivateKey(s) => write!(f, "Private key error: {}", s),
+            Error::ConnectAttemptsExhausted { attempts, last_error } =>
+                write!(f, "Gave up connecting after {} attempts, last error: {}",
+                       attempts, last_error),
+            Error::Timeout { operation, after } =>
+                write!(f, "Timeout {} after {}ms", operation, after.as_millis()),
+            Error::ConnectionRefused(code) => write!(f, "Connection refused: {:?}", code),
+            Error::ProtocolViolation { expected, got } =>
+                write!(f, "Protocol violation: expected {}, got {}", expected, got),
+            Error::PidsExhausted => write!(f, "No free Pids"),
+            Error::SessionStoreFull => write!(f, "Session store full"),
+            Error::InvalidState(s) => write!(f, "{}", s),
+            Error::Tls(e) => write!(f, "TLS error: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Proxy(s) => write!(f, "Proxy error: {}", s),
             Error::_NonExhaustive => panic!("Not reachable"),
         }
     }
 }
 
+fn fmt_reason_string(s: &Option<String>) -> String {
+    match s {
+        Some(s) => format!(": {}", s),
+        None => String::new(),
+    }
+}
+
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::StdError(e) => Some(&**e),
+            Error::Tls(e) => Some(&**e),
+            Error::Io(e) => Some(e),
             _ => None,
         }
     }
@@ -63,12 +174,18 @@ impl From<&str> for Error {
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
-        Error::StdError(Box::new(e))
+        Error::Io(e)
     }
 }
 
 impl From<mqttrs::Error> for Error {
     fn from(e: mqttrs::Error) -> Error {
-        Error::StdError(Box::new(e))
+        match e {
+            mqttrs::Error::IoError(kind, s) => Error::Io(std::io::Error::new(kind, s)),
+            e => Error::ProtocolViolation {
+                expected: "a well-formed MQTT 3.1.1 packet".to_owned(),
+                got: e.to_string(),
+            },
+        }
     }
 }
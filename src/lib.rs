@@ -1,4 +1,7 @@
-//! An MQTT 3.1.1 client written in Rust.
+//! An MQTT 3.1.1 and MQTT 5 client written in Rust.
+//!
+//! MQTT 3.1.1 is used by default. To use MQTT 5 call
+//! `ClientBuilder::set_protocol_version(ProtocolVersion::V5)`.
 //!
 //! For example usage see the command-line test app at
 //! `examples/mqttc.rs`, and integration tests at `tests/*.rs`.
@@ -13,6 +16,13 @@
 //! If TLS is not required you can opt out by specifying
 //! `default-features = false`.
 //! E.g. `mqtt-async-client = { version = "0.1", default-features = false }`
+//!
+//! The "websocket" feature is disabled by default and allows
+//! connections over WebSocket, see `ClientBuilder::set_websocket`.
+//!
+//! The "serde" feature is disabled by default and adds
+//! `client::ClientConfig`, which loads client options from a
+//! configuration file.
 #![deny(warnings)]
 #![deny(missing_docs)]
 
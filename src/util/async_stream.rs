@@ -1,23 +1,27 @@
-use tokio::{
-    io::{
-        AsyncRead,
-        AsyncWrite,
-        ReadBuf
-    },
-    net::TcpStream
+use crate::client::Transport;
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    ReadBuf
 };
 #[cfg(feature = "tls")]
 use tokio_rustls::client::TlsStream;
+#[cfg(feature = "websocket")]
+use crate::util::WebSocketStream;
 use std::{
     pin::Pin,
     task::{Context, Poll},
 };
 
-/// A wrapper for the data connection, which may or may not be encrypted.
+/// A wrapper for the data connection, which may or may not be encrypted
+/// or carried over WebSocket.
 pub(crate) enum AsyncStream {
-    TcpStream(TcpStream),
+    /// A connection opened by a `Connector`.
+    Transport(Box<dyn Transport>),
     #[cfg(feature = "tls")]
-    TlsStream(TlsStream<TcpStream>),
+    TlsStream(TlsStream<Box<dyn Transport>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WebSocketStream>),
 }
 
 impl AsyncRead for AsyncStream {
@@ -27,9 +31,11 @@ impl AsyncRead for AsyncStream {
         buf: &mut ReadBuf,
     ) -> Poll<std::io::Result<()>> {
         match Pin::get_mut(self) {
-            AsyncStream::TcpStream(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            AsyncStream::Transport(t) => Pin::new(t).poll_read(cx, buf),
             #[cfg(feature = "tls")]
             AsyncStream::TlsStream(tls) => Pin::new(tls).poll_read(cx, buf),
+            #[cfg(feature = "websocket")]
+            AsyncStream::WebSocket(ws) => Pin::new(ws.as_mut()).poll_read(cx, buf),
         }
     }
 }
@@ -41,18 +47,22 @@ impl AsyncWrite for AsyncStream {
         buf: &[u8]
     ) -> Poll<std::result::Result<usize, tokio::io::Error>> {
         match Pin::get_mut(self) {
-            AsyncStream::TcpStream(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            AsyncStream::Transport(t) => Pin::new(t).poll_write(cx, buf),
             #[cfg(feature = "tls")]
             AsyncStream::TlsStream(tls) => Pin::new(tls).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            AsyncStream::WebSocket(ws) => Pin::new(ws.as_mut()).poll_write(cx, buf),
         }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context
     ) -> Poll<std::result::Result<(), tokio::io::Error>> {
         match Pin::get_mut(self) {
-            AsyncStream::TcpStream(tcp) => Pin::new(tcp).poll_flush(cx),
+            AsyncStream::Transport(t) => Pin::new(t).poll_flush(cx),
             #[cfg(feature = "tls")]
             AsyncStream::TlsStream(tls) => Pin::new(tls).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            AsyncStream::WebSocket(ws) => Pin::new(ws.as_mut()).poll_flush(cx),
         }
     }
 
@@ -61,9 +71,11 @@ impl AsyncWrite for AsyncStream {
         cx: &mut Context
     ) -> Poll<std::result::Result<(), tokio::io::Error>> {
         match Pin::get_mut(self) {
-            AsyncStream::TcpStream(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            AsyncStream::Transport(t) => Pin::new(t).poll_shutdown(cx),
             #[cfg(feature = "tls")]
             AsyncStream::TlsStream(tls) => Pin::new(tls).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            AsyncStream::WebSocket(ws) => Pin::new(ws.as_mut()).poll_shutdown(cx),
         }
     }
 }
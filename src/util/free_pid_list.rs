@@ -15,11 +15,6 @@ pub struct FreePidList {
 }
 
 impl FreePidList {
-    /// Returns a new instance with all pids available.
-    pub fn new() -> FreePidList {
-        Self::with_bounds(1, std::u16::MAX)
-    }
-
     pub fn with_bounds(lb: u16, ub: u16) -> FreePidList {
         assert!(lb <= ub, "lb <= ub");
         assert!(lb >= 1, "lb >= 1");
@@ -137,7 +132,7 @@ mod tests {
 
     #[test]
     fn ex_1() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         assert_eq!(l.map, btreemap!{1 => std::u16::MAX});
 
         let a = l.alloc().unwrap();
@@ -164,7 +159,7 @@ mod tests {
 
     #[test]
     fn empty() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         for _ in 1..=std::u16::MAX {
             l.alloc().unwrap();
         }
@@ -191,7 +186,7 @@ mod tests {
 
     #[test]
     fn free_case_merge_below() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         l.alloc().unwrap();
         l.alloc().unwrap();
         l.alloc().unwrap();
@@ -203,7 +198,7 @@ mod tests {
 
     #[test]
     fn free_case_merge_above() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         l.alloc().unwrap();
         l.alloc().unwrap();
         l.alloc().unwrap();
@@ -215,7 +210,7 @@ mod tests {
 
     #[test]
     fn free_case_merge_above_and_below() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         l.alloc().unwrap();
         l.alloc().unwrap();
         l.free(1);
@@ -226,7 +221,7 @@ mod tests {
 
     #[test]
     fn free_case_new_range() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         l.alloc().unwrap();
         l.alloc().unwrap();
         assert_eq!(l.map, btreemap!{3 => std::u16::MAX});
@@ -236,21 +231,21 @@ mod tests {
 
     #[test]
     fn double_free_lower_bound() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         assert_eq!(l.map, btreemap!{1 => std::u16::MAX});
         assert_eq!(l.free(1), true);
     }
 
     #[test]
     fn double_free_upper_bound() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         assert_eq!(l.map, btreemap!{1 => std::u16::MAX});
         assert_eq!(l.free(std::u16::MAX), true);
     }
 
     #[test]
     fn clear() {
-        let mut l = FreePidList::new();
+        let mut l = FreePidList::with_bounds(1, std::u16::MAX);
         l.alloc().unwrap();
         assert_eq!(l.map, btreemap!{2 => std::u16::MAX});
         l.clear();
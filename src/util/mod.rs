@@ -6,5 +6,28 @@ pub(crate) use async_stream::AsyncStream;
 mod free_pid_list;
 pub(crate) use free_pid_list::FreePidList;
 
+mod url;
+pub(crate) use url::{
+    parse_query,
+    Url,
+};
+
 mod tokio_runtime;
 pub use tokio_runtime::TokioRuntime;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::{
+    load_certs,
+    load_certs_file,
+    load_private_key,
+    load_private_key_file,
+};
+#[cfg(feature = "tls")]
+pub(crate) use tls::default_client_config;
+
+#[cfg(feature = "websocket")]
+mod websocket_stream;
+#[cfg(feature = "websocket")]
+pub(crate) use websocket_stream::WebSocketStream;
@@ -0,0 +1,174 @@
+use crate::{
+    Error,
+    Result,
+};
+use rustls::{
+    Certificate,
+    ClientConfig,
+    PrivateKey,
+};
+use rustls_pemfile::Item;
+use std::path::Path;
+
+/// A TLS client config for a URL or config file that enables TLS.
+///
+/// It trusts just the CA certificates added later if `ca_certs` is
+/// true, otherwise the public CA certificates from webpki-roots.
+pub(crate) fn default_client_config(ca_certs: bool) -> ClientConfig {
+    let mut cc = ClientConfig::new();
+    if !ca_certs {
+        cc.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    cc
+}
+
+/// Load X.509 certificates from PEM or DER bytes.
+///
+/// PEM input may contain several certificates, e.g. a certificate
+/// chain or a CA bundle. Other PEM blocks are ignored. DER input
+/// contains a single certificate.
+pub fn load_certs(bytes: &[u8]) -> Result<Vec<Certificate>> {
+    if is_der(bytes) {
+        return Ok(vec![Certificate(bytes.to_vec())]);
+    }
+    let certs = pem_items(bytes).map_err(Error::Certificate)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(Error::Certificate("No certificates found in PEM input".to_owned()));
+    }
+    Ok(certs)
+}
+
+/// Load X.509 certificates from a PEM or DER file. See `load_certs`.
+pub fn load_certs_file<P: AsRef<Path>>(path: P) -> Result<Vec<Certificate>> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::Certificate(format!("Error reading '{}': {}",
+                                                path.display(), e)))?;
+    load_certs(&bytes).map_err(|e| with_path(e, path))
+}
+
+/// Load a private key from PEM or DER bytes.
+///
+/// PKCS#8 keys (RSA or EC) and PKCS#1 RSA keys are supported. EC keys
+/// must use the P-256 or P-384 curve. rustls does not accept SEC1 EC
+/// keys ("BEGIN EC PRIVATE KEY"), convert them to PKCS#8 with
+/// `openssl pkcs8 -topk8 -nocrypt`. PEM input must contain exactly one
+/// private key; other PEM blocks are ignored.
+pub fn load_private_key(bytes: &[u8]) -> Result<PrivateKey> {
+    let der = if is_der(bytes) {
+        bytes.to_vec()
+    } else {
+        let mut keys = vec![];
+        for item in pem_items(bytes).map_err(Error::PrivateKey)? {
+            match item {
+                Item::PKCS8Key(der) | Item::RSAKey(der) => keys.push(der),
+                Item::ECKey(_) => return Err(Error::PrivateKey(
+                    "SEC1 EC private keys are not supported, convert the key to PKCS#8 \
+                     with `openssl pkcs8 -topk8 -nocrypt`".to_owned())),
+                _ => (),
+            }
+        }
+        match keys.len() {
+            0 => return Err(Error::PrivateKey("No private key found in PEM input".to_owned())),
+            1 => keys.remove(0),
+            n => return Err(Error::PrivateKey(
+                format!("Expected one private key in PEM input, found {}", n))),
+        }
+    };
+    let key = PrivateKey(der);
+    if rustls::sign::any_supported_type(&key).is_err() {
+        return Err(Error::PrivateKey("Unsupported or malformed private key".to_owned()));
+    }
+    Ok(key)
+}
+
+/// Load a private key from a PEM or DER file. See `load_private_key`.
+pub fn load_private_key_file<P: AsRef<Path>>(path: P) -> Result<PrivateKey> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)
+        .map_err(|e| Error::PrivateKey(format!("Error reading '{}': {}",
+                                               path.display(), e)))?;
+    load_private_key(&bytes).map_err(|e| with_path(e, path))
+}
+
+/// Add the path to a loading error message.
+fn with_path(e: Error, path: &Path) -> Error {
+    match e {
+        Error::Certificate(s) => Error::Certificate(format!("'{}': {}", path.display(), s)),
+        Error::PrivateKey(s) => Error::PrivateKey(format!("'{}': {}", path.display(), s)),
+        e => e,
+    }
+}
+
+/// DER always starts with a SEQUENCE tag, PEM with text.
+fn is_der(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&0x30)
+}
+
+/// Returns the PEM blocks that rustls-pemfile recognises.
+fn pem_items(mut bytes: &[u8]) -> std::result::Result<Vec<Item>, String> {
+    rustls_pemfile::read_all(&mut bytes).map_err(|e| format!("Bad PEM input: {}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CA_CERT: &[u8] = include_bytes!("../../tests/certs/cacert.pem");
+    const CLIENT_CERT: &[u8] = include_bytes!("../../tests/certs/clientcert.pem");
+    // PKCS#8 EC private key
+    const CLIENT_KEY: &[u8] = include_bytes!("../../tests/certs/clientkey.pem");
+    // The same key in SEC1 form
+    const CLIENT_KEY_SEC1: &[u8] = include_bytes!("../../tests/certs/clientkey-sec1.pem");
+    // PKCS#8 RSA private key
+    const SERVER_KEY: &[u8] = include_bytes!("../../tests/certs/serverkey.pem");
+
+    #[test]
+    fn certs_pem_and_der() {
+        let mut bundle = CA_CERT.to_vec();
+        bundle.extend_from_slice(CLIENT_CERT);
+        let certs = load_certs(&bundle).unwrap();
+        assert_eq!(certs.len(), 2);
+
+        let der = load_certs(&certs[0].0).unwrap();
+        assert_eq!(der, vec![certs[0].clone()]);
+    }
+
+    #[test]
+    fn certs_errors() {
+        assert!(matches!(load_certs(b"not a cert"), Err(Error::Certificate(_))));
+        assert!(matches!(load_certs(SERVER_KEY), Err(Error::Certificate(_))));
+        assert!(matches!(load_certs_file("/nonexistent.pem"), Err(Error::Certificate(_))));
+    }
+
+    #[test]
+    fn private_keys() {
+        let ec = load_private_key(CLIENT_KEY).unwrap();
+        load_private_key(&ec.0).unwrap();
+
+        let rsa = load_private_key(SERVER_KEY).unwrap();
+        load_private_key(&rsa.0).unwrap();
+    }
+
+    #[test]
+    fn private_key_errors() {
+        assert!(matches!(load_private_key(CA_CERT), Err(Error::PrivateKey(_))));
+        assert!(matches!(load_private_key(&[0x30, 0x03, 0x02, 0x01, 0x00]),
+                         Err(Error::PrivateKey(_))));
+        let mut two = CLIENT_KEY.to_vec();
+        two.extend_from_slice(SERVER_KEY);
+        assert!(matches!(load_private_key(&two), Err(Error::PrivateKey(_))));
+        assert!(matches!(load_private_key_file("/nonexistent.pem"),
+                         Err(Error::PrivateKey(_))));
+        match load_private_key(CLIENT_KEY_SEC1) {
+            Err(Error::PrivateKey(e)) => assert!(e.contains("PKCS#8"), "{}", e),
+            r => panic!("Expected an error, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    client::WebSocketOptions,
+    Error,
+    Result,
+    util::AsyncStream,
+};
+use futures_core::Stream;
+use futures_util::sink::Sink;
+use std::{
+    cmp::min,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    ReadBuf,
+};
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::client::Request,
+    Message,
+};
+
+/// Adapts a WebSocket connection to `AsyncRead` and `AsyncWrite`.
+///
+/// Each write is sent as one binary message. Reads return the
+/// contents of binary messages in order.
+pub(crate) struct WebSocketStream {
+    ws: tokio_tungstenite::WebSocketStream<AsyncStream>,
+
+    /// The last binary message received.
+    read_buf: Vec<u8>,
+
+    /// How much of `read_buf` has already been read.
+    read_pos: usize,
+}
+
+impl WebSocketStream {
+    /// Perform the WebSocket handshake for `url` over `stream`.
+    pub(crate) async fn connect(
+        stream: AsyncStream,
+        url: String,
+        opts: &WebSocketOptions,
+    ) -> Result<WebSocketStream> {
+        let mut req = Request::builder()
+            .uri(url)
+            .header("Sec-WebSocket-Protocol", "mqtt");
+        for (name, value) in opts.headers() {
+            req = req.header(name.as_str(), value.as_str());
+        }
+        let req = req.body(()).map_err(Error::from_std_err)?;
+        let (ws, _res) = tokio_tungstenite::client_async(req, stream).await
+            .map_err(Error::from_std_err)?;
+        Ok(WebSocketStream {
+            ws,
+            read_buf: vec![],
+            read_pos: 0,
+        })
+    }
+}
+
+fn to_io_err(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl AsyncRead for WebSocketStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = Pin::get_mut(self);
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = min(buf.remaining(), this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..(this.read_pos + n)]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            let msg = match Pin::new(&mut this.ws).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // Closed, so return no bytes to signal EOF.
+                Poll::Ready(None) |
+                Poll::Ready(Some(Ok(Message::Close(_)))) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(to_io_err(e))),
+                Poll::Ready(Some(Ok(msg))) => msg,
+            };
+            match msg {
+                Message::Binary(data) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                },
+                Message::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Received text WebSocket message, MQTT requires binary")));
+                },
+                // tungstenite answers pings itself.
+                _ => (),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WebSocketStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8]
+    ) -> Poll<io::Result<usize>> {
+        let this = Pin::get_mut(self);
+        match Pin::new(&mut this.ws).poll_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(to_io_err(e))),
+            Poll::Ready(Ok(())) => (),
+        }
+        Pin::new(&mut this.ws).start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_err)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut Pin::get_mut(self).ws).poll_flush(cx).map_err(to_io_err)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut Pin::get_mut(self).ws).poll_close(cx).map_err(to_io_err)
+    }
+}
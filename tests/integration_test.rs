@@ -8,27 +8,52 @@
 //! ${MOSQUITTO_PATH}/mosquitto -c mosquitto.conf
 //! ```
 //!
-//! This will run an unencrypted listener at localhost:1883, and a TLS
+//! This will run an unencrypted listener at localhost:1883, a TLS
 //! encrypted listener at localhost:8883, using the certificates and
 //! keys in ${REPO}/tests/certs, which were generated using these
-//! instructions: <https://stackoverflow.com/a/21340898/94819>
+//! instructions: <https://stackoverflow.com/a/21340898/94819>, a TLS
+//! encrypted listener that requires a client certificate at
+//! localhost:8884, and an unencrypted WebSocket listener at
+//! localhost:8080.
 
 #![deny(warnings)]
 
 use mqtt_async_client::{
     client::{
         Client,
+        ConnectionEvent,
+        DisconnectReason,
+        Endpoint,
+        EndpointPolicy,
+        ExponentialBackoff,
+        FileStore,
+        LastWill,
+        MaxAttempts,
+        Properties,
+        ProtocolVersion,
         Publish,
         QoS,
+        ReadResult,
+        ReasonCode,
         Subscribe,
         SubscribeTopic,
+        Subscription,
         Unsubscribe,
         UnsubscribeTopic,
     },
+    Error,
     Result,
 };
 #[cfg(feature = "tls")]
-use mqtt_async_client::Error;
+use mqtt_async_client::{
+    util::{
+        load_certs,
+        load_private_key,
+    },
+};
+#[cfg(feature = "websocket")]
+use mqtt_async_client::client::WebSocketOptions;
+use futures_util::StreamExt;
 #[cfg(feature = "tls")]
 use rustls;
 use std::sync::Once;
@@ -71,6 +96,45 @@ fn pub_and_sub_plain() -> Result<()> {
     })
 }
 
+#[cfg(feature = "tls")]
+#[test]
+fn pub_and_sub_tls_client_cert() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(8884)
+            .set_tls_ca_certs(load_certs(include_bytes!("certs/cacert.pem"))?)
+            .set_tls_client_cert(load_certs(include_bytes!("certs/clientcert.pem"))?,
+                                 load_private_key(include_bytes!("certs/clientkey.pem"))?)
+            .set_connect_retry_delay(Duration::from_secs(1))
+            .build()?;
+        c.connect().await?;
+
+        // Subscribe
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtMostOnce,
+                             topic_path: "test/pub_and_sub_tls_client_cert".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+
+        // Publish
+        let mut p = Publish::new("test/pub_and_sub_tls_client_cert".to_owned(),
+                                 "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtMostOnce);
+        c.publish(&p).await?;
+
+        // Read
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/pub_and_sub_tls_client_cert");
+        assert_eq!(r.payload(), b"x");
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
 #[cfg(feature = "tls")]
 #[test]
 fn pub_and_sub_tls() -> Result<()> {
@@ -101,6 +165,69 @@ fn pub_and_sub_tls() -> Result<()> {
     })
 }
 
+#[test]
+fn publish_qos2() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        c.connect().await?;
+
+        // Subscribe
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::ExactlyOnce, topic_path: "test/publish_qos2".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+
+        // Publish
+        let mut p = Publish::new("test/publish_qos2".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::ExactlyOnce);
+        c.publish(&p).await?;
+
+        // Read
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/publish_qos2");
+        assert_eq!(r.payload(), b"x");
+        assert_eq!(r.qos(), QoS::ExactlyOnce);
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn pub_and_sub_qos2() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        c.connect().await?;
+
+        // Subscribe
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::ExactlyOnce, topic_path: "test/pub_and_sub_qos2".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+
+        // Publish
+        let mut p = Publish::new("test/pub_and_sub_qos2".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::ExactlyOnce);
+        c.publish(&p).await?;
+
+        // Read
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/pub_and_sub_qos2");
+        assert_eq!(r.payload(), b"x");
+
+        // Exactly once, so no more copies.
+        let r = timeout(Duration::from_secs(3), c.read_subscriptions()).await;
+        assert!(r.is_err());
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
 #[test]
 fn unsubscribe() -> Result<()> {
     init_logger();
@@ -159,11 +286,475 @@ fn retain() -> Result<()> {
         let r = c.read_subscriptions().await?;
         assert_eq!(r.topic(), "test/retain");
         assert_eq!(r.payload(), b"x");
+        assert!(r.retain());
+        assert!(!r.dup());
+        assert_eq!(r.qos(), QoS::AtMostOnce);
+        assert_eq!(r.pid(), None);
+        assert_eq!(r.subscription_filter(), Some("test/retain"));
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn persistent_session() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let client_id = "persistent_session".to_owned();
+
+        // Start a fresh session.
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_client_id(Some(client_id.clone()))
+            .build()?;
+        c.connect().await?;
+        c.disconnect().await?;
+
+        // Subscribe with a persistent session, then disconnect.
+        let mut c = persistent_client(client_id.clone())?;
+        c.connect().await?;
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/persistent_session".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+        c.disconnect().await?;
+
+        // Publish while the subscriber is offline.
+        let mut publisher = plain_client()?;
+        publisher.connect().await?;
+        let mut p = Publish::new("test/persistent_session".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        publisher.publish(&p).await?;
+        publisher.disconnect().await?;
+
+        // Resume the session and read the queued message.
+        let mut c = persistent_client(client_id)?;
+        c.connect().await?;
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/persistent_session");
+        assert_eq!(r.payload(), b"x");
+        assert!(c.session_present()?);
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn manual_acks() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let client_id = "manual_acks".to_owned();
+        let client = |client_id: String| Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_client_id(Some(client_id))
+            .set_clean_session(false)
+            .set_manual_acks(true)
+            .build();
+
+        // Start a fresh session.
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_client_id(Some(client_id.clone()))
+            .build()?;
+        c.connect().await?;
+        c.disconnect().await?;
+
+        // Read a message without acknowledging it.
+        let mut c = client(client_id.clone())?;
+        c.connect().await?;
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/manual_acks".to_owned() }
+            ]);
+        c.subscribe(subopts).await?.any_failures()?;
+        let mut p = Publish::new("test/manual_acks".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        c.publish(&p).await?;
+        let mut r = c.read_subscriptions().await?;
+        assert!(!r.dup());
+        assert!(r.take_ack().is_some());
+        c.disconnect().await?;
+
+        // The broker redelivers it when the session resumes.
+        let mut c = client(client_id)?;
+        c.connect().await?;
+        let mut r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/manual_acks");
+        assert!(r.dup());
+        r.ack().await?;
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn last_will() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut sub = plain_client()?;
+        sub.connect().await?;
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtMostOnce, topic_path: "test/last_will".to_owned() }
+            ]);
+        let subres = sub.subscribe(subopts).await?;
+        subres.any_failures()?;
+
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_last_will(Some(LastWill::new("test/last_will".to_owned(),
+                                              "offline".as_bytes().to_vec())))
+            .build()?;
+        c.connect().await?;
+        // Make sure the connection is up before dropping it.
+        let mut p = Publish::new("test/last_will/ping".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        c.publish(&p).await?;
+
+        // Dropping the client closes the connection without sending
+        // Disconnect, so the broker publishes the last will.
+        drop(c);
+
+        let r = sub.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/last_will");
+        assert_eq!(r.payload(), b"offline");
+        sub.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn pub_and_sub_v5() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_protocol_version(ProtocolVersion::V5)
+            .set_connect_retry_delay(Duration::from_secs(1))
+            .build()?;
+        c.connect().await?;
+
+        // Subscribe
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/pub_and_sub_v5".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+        assert_eq!(subres.reason_codes(), &[ReasonCode::GrantedQoS1]);
+
+        // Publish
+        let mut p = Publish::new("test/pub_and_sub_v5".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        let mut props = Properties::default();
+        props.content_type = Some("text/plain".to_owned());
+        props.user_properties = vec![("k".to_owned(), "v".to_owned())];
+        p.set_properties(props);
+        c.publish(&p).await?;
+
+        // Read
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/pub_and_sub_v5");
+        assert_eq!(r.payload(), b"x");
+        assert_eq!(r.properties().content_type.as_deref(), Some("text/plain"));
+        assert_eq!(r.properties().user_properties,
+                   vec![("k".to_owned(), "v".to_owned())]);
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn pub_and_sub_websocket() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(8080)
+            .set_websocket(Some(WebSocketOptions::new()))
+            .set_connect_retry_delay(Duration::from_secs(1))
+            .build()?;
+        c.connect().await?;
+
+        // Subscribe
+        let subopts = Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/pub_and_sub_websocket".to_owned() }
+            ]);
+        let subres = c.subscribe(subopts).await?;
+        subres.any_failures()?;
+
+        // Publish
+        let mut p = Publish::new("test/pub_and_sub_websocket".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        c.publish(&p).await?;
+
+        // Read
+        let r = c.read_subscriptions().await?;
+        assert_eq!(r.topic(), "test/pub_and_sub_websocket");
+        assert_eq!(r.payload(), b"x");
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn failover() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        // Nothing listens on port 1, so the client falls back to 1883.
+        let mut c = Client::builder()
+            .set_endpoints(vec![Endpoint::new("localhost".to_owned(), 1),
+                                Endpoint::new("localhost".to_owned(), 1883)])
+            .set_endpoint_policy(EndpointPolicy::Primary)
+            .set_connect_retry_delay(Duration::from_secs(1))
+            .build()?;
+        c.connect().await?;
+
+        let mut p = Publish::new("test/failover".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        c.publish(&p).await?;
+
+        let ep = c.connected_endpoint()?.expect("connected");
+        assert_eq!(ep.port(), 1883);
         c.disconnect().await?;
         Ok(())
     })
 }
 
+#[test]
+fn reconnect_gives_up() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        // Nothing listens on port 1.
+        let mut c = Client::builder()
+            .set_endpoints(vec![Endpoint::new("localhost".to_owned(), 1)])
+            .set_reconnect_policy(MaxAttempts::new(
+                ExponentialBackoff::new(Duration::from_millis(10), Duration::from_millis(100)),
+                3))
+            .build()?;
+        c.connect().await?;
+
+        let mut p = Publish::new("test/gives_up".to_owned(), "x".as_bytes().to_vec());
+        p.set_qos(QoS::AtLeastOnce);
+        match c.publish(&p).await {
+            Err(Error::ConnectAttemptsExhausted { attempts: 3, .. }) => (),
+            r => panic!("Unexpected publish result: {:?}", r),
+        }
+        match c.read_subscriptions().await {
+            Err(Error::ConnectAttemptsExhausted { .. }) => (),
+            r => panic!("Unexpected read result: {:?}", r),
+        }
+        Ok(())
+    })
+}
+
+#[test]
+fn client_handle() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        c.connect().await?;
+        let h = c.handle()?;
+        let tasks = (0..4).map(|i| {
+            let h = h.clone();
+            tokio::spawn(async move {
+                let topic = format!("test/client_handle/{}", i);
+                h.subscribe(Subscribe::new(vec![
+                    SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: topic.clone() },
+                ])).await?.any_failures()?;
+                let mut p = Publish::new(topic, "x".as_bytes().to_vec());
+                p.set_qos(QoS::AtLeastOnce);
+                h.publish(&p).await
+            })
+        }).collect::<Vec<_>>();
+        for t in tasks {
+            t.await.map_err(Error::from_std_err)??;
+        }
+        for _ in 0..4 {
+            let r = c.read_subscriptions().await?;
+            assert!(r.topic().starts_with("test/client_handle/"));
+        }
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn max_in_flight() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_max_in_flight(2)
+            .build()?;
+        c.connect().await?;
+        let h = c.handle()?;
+        // More publishes than the limit wait for a free packet ID.
+        let tasks = (0..20).map(|i| {
+            let h = h.clone();
+            tokio::spawn(async move {
+                let mut p = Publish::new(format!("test/max_in_flight/{}", i),
+                                         "x".as_bytes().to_vec());
+                p.set_qos(QoS::AtLeastOnce);
+                h.publish(&p).await
+            })
+        }).collect::<Vec<_>>();
+        for t in tasks {
+            t.await.map_err(Error::from_std_err)??;
+        }
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn session_store() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut sub = plain_client()?;
+        sub.connect().await?;
+        sub.subscribe(Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/session_store/+".to_owned() },
+        ])).await?.any_failures()?;
+
+        let path = std::env::temp_dir().join("mqtt-async-client-session-store.log");
+        let _ = std::fs::remove_file(&path);
+        {
+            // Nothing listens on port 1, so the publishes stay in the store.
+            let mut c = Client::builder()
+                .set_endpoints(vec![Endpoint::new("localhost".to_owned(), 1)])
+                .set_session_store(FileStore::open(&path)?)
+                .build()?;
+            c.connect().await?;
+            for i in 0..3 {
+                let mut p = Publish::new(format!("test/session_store/{}", i),
+                                         "x".as_bytes().to_vec());
+                p.set_qos(QoS::AtLeastOnce);
+                c.publish(&p).await?;
+            }
+        }
+
+        // A new client sends them once it connects.
+        let mut c = Client::builder()
+            .set_host("localhost".to_owned())
+            .set_port(1883)
+            .set_session_store(FileStore::open(&path)?)
+            .build()?;
+        c.connect().await?;
+        for i in 0..3 {
+            let r = timeout(Duration::from_secs(5), sub.read_subscriptions()).await
+                .map_err(Error::from_std_err)??;
+            assert_eq!(r.topic(), format!("test/session_store/{}", i));
+        }
+        c.disconnect().await?;
+        sub.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn subscribe_stream() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        c.connect().await?;
+        let sub = |filter: &str| Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: filter.to_owned() },
+        ]);
+        let mut one = c.subscribe_stream(sub("test/subscribe_stream/1/+")).await?;
+        one.result().any_failures()?;
+        let mut all = c.subscribe_stream(sub("test/subscribe_stream/#")).await?;
+        all.result().any_failures()?;
+
+        for t in ["test/subscribe_stream/1/a", "test/subscribe_stream/2/a"].iter() {
+            let mut p = Publish::new((*t).to_owned(), "x".as_bytes().to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            c.publish(&p).await?;
+        }
+        assert_eq!(next_message(&mut one).await?.topic(), "test/subscribe_stream/1/a");
+        assert_eq!(next_message(&mut all).await?.topic(), "test/subscribe_stream/1/a");
+        assert_eq!(next_message(&mut all).await?.topic(), "test/subscribe_stream/2/a");
+        assert!(next_message(&mut one).await.is_err());
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn messages_stream() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        c.connect().await?;
+        c.subscribe(Subscribe::new(vec![
+            SubscribeTopic { qos: QoS::AtLeastOnce, topic_path: "test/messages_stream/+".to_owned() },
+        ])).await?.any_failures()?;
+        let messages = c.messages()?;
+
+        for i in 0..3 {
+            let mut p = Publish::new(format!("test/messages_stream/{}", i),
+                                     "x".as_bytes().to_vec());
+            p.set_qos(QoS::AtLeastOnce);
+            c.publish(&p).await?;
+        }
+        let topics = messages.take(3)
+            .map(|r| r.map(|r| r.topic().to_owned()))
+            .collect::<Vec<_>>().await
+            .into_iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(topics, vec!["test/messages_stream/0",
+                                "test/messages_stream/1",
+                                "test/messages_stream/2"]);
+        c.disconnect().await?;
+        Ok(())
+    })
+}
+
+#[test]
+fn connection_events() -> Result<()> {
+    init_logger();
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        let mut c = plain_client()?;
+        let mut events = c.events();
+        c.connect().await?;
+        c.disconnect().await?;
+
+        let mut seen = vec![];
+        for _ in 0..3 {
+            let e = timeout(Duration::from_secs(5), events.next()).await
+                .map_err(Error::from_std_err)?;
+            seen.push(e.expect("event"));
+        }
+        assert!(matches!(seen[0], ConnectionEvent::Connecting));
+        assert!(matches!(seen[1], ConnectionEvent::Connected { session_present: false }));
+        assert!(matches!(seen[2],
+                         ConnectionEvent::Disconnected { reason: DisconnectReason::Client }));
+        Ok(())
+    })
+}
+
+async fn next_message(s: &mut Subscription) -> Result<ReadResult> {
+    let r = timeout(Duration::from_secs(3), s.next()).await.map_err(Error::from_std_err)?;
+    r.ok_or_else(|| Error::from("Subscription ended"))
+}
+
 #[cfg(feature = "tls")]
 fn tls_client() -> Result<Client> {
     let mut cc = rustls::ClientConfig::new();
@@ -188,6 +779,16 @@ fn plain_client() -> Result<Client> {
         .build()
 }
 
+fn persistent_client(client_id: String) -> Result<Client> {
+    Client::builder()
+        .set_host("localhost".to_owned())
+        .set_port(1883)
+        .set_client_id(Some(client_id))
+        .set_clean_session(false)
+        .set_connect_retry_delay(Duration::from_secs(1))
+        .build()
+}
+
 static LOGGER_INIT: Once = Once::new();
 
 fn init_logger() {